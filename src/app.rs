@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::path::Path;
 use std::time::Duration;
 use std::io::{Write, stdout};
 
@@ -6,7 +6,9 @@ use crossterm::execute;
 use crossterm::event::{
     KeyboardEnhancementFlags,
     PushKeyboardEnhancementFlags,
-    PopKeyboardEnhancementFlags
+    PopKeyboardEnhancementFlags,
+    EnableMouseCapture,
+    DisableMouseCapture,
 };
 use fundsp::funutd::Rnd;
 use fundsp::hacker::*;
@@ -15,70 +17,137 @@ use crossterm::{event, event::*};
 use crossterm::event::{KeyCode, KeyEventKind};
 
 use crate::{command_box::CommandBox, event_handler::EventHandler, patch::Patch, sequence::Sequence, track::Track};
+use crate::patch_cache::PatchCache;
+use crate::patch_editor::PatchEditor;
+use crate::sequence_editor::SequenceEditor;
+use crate::sequence;
+use crate::track::Section;
+use crate::transport::{Clock, Transport, TransportState};
+use crate::voice::{self, VoiceAllocator, VoiceInputs};
+use crate::render::{self, BitDepth};
+use crate::command_line::{self, Arg, CommandSpec, ParseError, Time};
+use crate::drum_pads::{DrumPads, PadSound};
 use crate::keyboard::{Keyboard, Note, NoteEvent, NoteEventKind};
+use crate::midi::{MidiIn, MidiMessage};
 use crate::frame_renderable::FrameRenderable;
+use crate::history::History;
+use crate::script;
 
 
-#[derive(Debug)]
-pub enum Arg {
-    None,
-    Path(String),
-    PatchName,
-    NewPatchName,
-    SequenceName,
-    NewSequenceName,
-    // TODO: others
-}
-
 #[derive(Debug)]
 enum AppCommand {
-    Exit, Play,
+    Exit, Keys, Pads,
+    Play,
+    PlaySequence(String),
+    PlayTrack,
+    Pause,
+    Stop,
+    Seek(Time),
+    Loop(bool),
+    LoopRegion(Time, Time),
     LoadTrack(String),
     LoadPatch(String),
     LoadSequence(String),
     EditPatch(String),
     CreatePatch(String),
-    CreateSequence(String)
+    CreateSequence(String),
+    EditSequence(String),
+    Render(String),
+    SaveTrack(String),
+    SavePatch(String),
+    SaveSequence(String),
+    MidiList,
+    MidiConnect(String),
+    MidiVirtual,
+    MidiDisconnect,
+    Source(String),
+    PadPatch(f64, String),
+    PadSample(f64, String),
+    PadChoke(f64, f64),
+    Help(String),
         // TODO: others
 }
 
 impl AppCommand {
-    fn list_commands() -> Vec<(String, Arg)> {
+    fn table() -> Vec<CommandSpec<Self>> {
         vec![
-            ("exit".into(), Arg::None),
-            ("play".into(), Arg::None),
-            ("load track".into(), Arg::Path("*.yaml".into())),
-            ("load patch".into(), Arg::Path("*.yaml".into())),
-            ("load sequence".into(), Arg::Path("*.yaml".into())),
-            ("create patch".into(), Arg::NewPatchName),
-            ("edit patch".into(), Arg::PatchName),
-            ("create sequence".into(), Arg::NewSequenceName),
-            ("edit sequence".into(), Arg::SequenceName),
+            CommandSpec::new("exit", vec![], "Quit doris.", |_| Self::Exit),
+            CommandSpec::new("keys", vec![], "Play the live patch from the computer keyboard; Esc comes back here.", |_| Self::Keys),
+            CommandSpec::new("pads", vec![], "Play the drum pads from the computer keyboard; Esc comes back here.", |_| Self::Pads),
+            CommandSpec::new("pad patch", vec![Arg::Number, Arg::PatchName], "Have a drum pad play a patch of the track.", |mut a| Self::PadPatch(a.number(), a.text())),
+            CommandSpec::new("pad sample", vec![Arg::Number, Arg::Path("*.wav")], "Have a drum pad play a WAV file.", |mut a| Self::PadSample(a.number(), a.text())),
+            CommandSpec::new("pad choke", vec![Arg::Number, Arg::Number], "Put a drum pad in a choke group, whose pads cut each other off; 0 for none.", |mut a| Self::PadChoke(a.number(), a.number())),
+            CommandSpec::new("play", vec![], "Start or resume the transport.", |_| Self::Play),
+            CommandSpec::new("play sequence", vec![Arg::SequenceName], "Play a sequence of the track on the transport.", |mut a| Self::PlaySequence(a.text())),
+            CommandSpec::new("play track", vec![], "Play the whole track on the transport.", |_| Self::PlayTrack),
+            CommandSpec::new("pause", vec![], "Pause the transport where it is.", |_| Self::Pause),
+            CommandSpec::new("stop", vec![], "Stop the transport and go back to the start.", |_| Self::Stop),
+            CommandSpec::new("seek", vec![Arg::Time], "Move the transport to a position.", |mut a| Self::Seek(a.time())),
+            CommandSpec::new("loop on", vec![], "Loop what the transport plays.", |_| Self::Loop(true)),
+            CommandSpec::new("loop off", vec![], "Play through once and stop.", |_| Self::Loop(false)),
+            CommandSpec::new("loop region", vec![Arg::Time, Arg::Time], "Loop between two positions.", |mut a| Self::LoopRegion(a.time(), a.time())),
+            CommandSpec::new("load track", vec![Arg::Path("*.yaml")], "Load a track, replacing the current one.", |mut a| Self::LoadTrack(a.text())),
+            CommandSpec::new("load patch", vec![Arg::Path("*.yaml")], "Load a patch to play live, without adding it to the track.", |mut a| Self::LoadPatch(a.text())),
+            CommandSpec::new("load sequence", vec![Arg::Path("*.yaml")], "Load a sequence into the track, named after its file.", |mut a| Self::LoadSequence(a.text())),
+            CommandSpec::new("create patch", vec![Arg::NewPatchName], "Add a new patch, a saw shaped by an envelope, to the track and play it live.", |mut a| Self::CreatePatch(a.text())),
+            CommandSpec::new("edit patch", vec![Arg::PatchName], "Play a patch of the track live.", |mut a| Self::EditPatch(a.text())),
+            CommandSpec::new("create sequence", vec![Arg::NewSequenceName], "Add an empty sequence to the track and open it in the step sequencer.", |mut a| Self::CreateSequence(a.text())),
+            CommandSpec::new("edit sequence", vec![Arg::SequenceName], "Open a sequence of the track in the step sequencer.", |mut a| Self::EditSequence(a.text())),
+            CommandSpec::new("render", vec![Arg::Path("*.wav")], "Render the track to a 16-bit WAV file.", |mut a| Self::Render(a.text())),
+            CommandSpec::new("save track", vec![Arg::Path("*.yaml")], "Save the track.", |mut a| Self::SaveTrack(a.text())),
+            CommandSpec::new("save patch", vec![Arg::Path("*.yaml")], "Save the live patch.", |mut a| Self::SavePatch(a.text())),
+            CommandSpec::new("save sequence", vec![Arg::Path("*.yaml")], "Save the sequence being edited.", |mut a| Self::SaveSequence(a.text())),
+            CommandSpec::new("midi list", vec![], "List MIDI input ports.", |_| Self::MidiList),
+            CommandSpec::new("midi connect", vec![Arg::MidiPort], "Play the live patch from a MIDI input port, by name or number.", |mut a| Self::MidiConnect(a.text())),
+            CommandSpec::new("midi virtual", vec![], "Open a virtual MIDI input port for other programs to play into.", |_| Self::MidiVirtual),
+            CommandSpec::new("midi disconnect", vec![], "Close the MIDI input port.", |_| Self::MidiDisconnect),
+            CommandSpec::new("source", vec![Arg::Path("*")], "Run the commands in a file.", |mut a| Self::Source(a.text())),
+            CommandSpec::new("help", vec![Arg::Command], "Show the commands, or how to use one.", |mut a| Self::Help(a.text())),
         ]
     }
+
+    fn list_commands() -> Vec<(String, Vec<Arg>)> {
+        Self::table().into_iter().map(|spec| (spec.words.to_string(), spec.args)).collect()
+    }
 }
 
 impl TryFrom<&str> for AppCommand {
-    type Error = String;
+    type Error = ParseError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let parts: Vec<_> = value.split(" ").map(|v|v).collect();
-        let p1 = parts.get(0).cloned();
-        let p2 = parts.get(1).cloned();
-        let p3 = parts.get(2).cloned();
-        match (p1, p2, p3) {
-            (Some("exit"), None, None) => Ok(AppCommand::Exit),
-            (Some("play"), None, None) => Ok(AppCommand::Play),
-            (Some("load"), Some("track"), Some(s)) => Ok(AppCommand::LoadTrack(s.into())),
-            _ => Err(format!("unrecognised command \"{value}\""))
-        }
+        command_line::parse(&Self::table(), value)
     }
 }
 
+/// Cache key of the patch played from the keyboard.
+const LIVE_PATCH: &str = "<live>";
+
+/// How long a drum pad holds its patch's gate open when the patch plays no samples.
+const PAD_GATE: f64 = 0.1;
+
+/// Name of the port `midi virtual` opens for other programs to play doris from.
+const MIDI_VIRTUAL_PORT: &str = "doris";
+
 #[derive(Clone, Copy)]
 enum Mode {
     Command,
     Play,
+    Pads,
+    Patch,
+    Sequence,
+}
+
+/// Which editor the workspace shows.
+#[derive(Clone, Copy)]
+enum Pane {
+    Patch,
+    Sequence,
+}
+
+/// What the transport was last loaded with, to reload after edits.
+enum Loaded {
+    Sequence(String),
+    Track,
 }
 
 
@@ -86,49 +155,95 @@ pub struct App {
     rng: Rnd,
     net: Net,
     seq: Sequencer,
-    seq_events: HashMap<(Note, i32), EventId>,
+    voices: VoiceAllocator,
+    transport: Transport,
     track: Track,
     patch: Patch,
+    /// Name in the track of the patch being played and edited, unless it came from elsewhere.
+    patch_name: Option<String>,
+    patch_cache: PatchCache,
     sequence: Sequence,
+    sequence_name: Option<String>,
     cbox: CommandBox,
     kb: Keyboard,
+    pads: DrumPads,
+    editor: PatchEditor,
+    seq_editor: SequenceEditor,
+    pane: Pane,
+    loaded: Option<Loaded>,
+    midi: MidiIn,
     mode: Mode,
+    sample_rate: f64,
+    /// How many scripts are running inside one another.
+    script_depth: usize,
+    /// Whether the terminal has been taken over, and needs restoring.
+    tui: bool,
 }
 
 
 impl App {
+    /// How deep scripts may source one another, so one sourcing itself stops.
+    const MAX_SCRIPT_DEPTH: usize = 8;
+
     pub fn new(mut net: Net, sample_rate: f64) -> Self {
         let mut seq = Sequencer::new(false, 1);
         seq.set_sample_rate(sample_rate);
+        let clock = Clock::new();
         net.chain(Box::new(seq.backend()));
+        net.chain(Box::new(An(clock.clone())));
         net.chain(Box::new(pan(0.0)));
         net.commit();
 
         let mut cbox = CommandBox::new();
         cbox.set_autocomplete(AppCommand::list_commands());
+        if let Some(path) = History::default_path() {
+            match History::open(path.clone()) {
+                Ok(history) => cbox.set_history(history),
+                Err(e) => cbox.push_error(format!("Failed to read history from \"{}\": {e}", path.display())),
+            }
+        }
+        let track = Track::new();
+        let patch = Patch::new();
         Self {
             rng: Rnd::from_u64(0),
             cbox,
             kb: Keyboard::new(),
-            track: Track::new(),
-            patch: Patch::new(),
+            pads: DrumPads::new(),
+            editor: PatchEditor::new(patch.clone(), None),
+            seq_editor: SequenceEditor::new(Sequence::new(), None),
+            pane: Pane::Patch,
+            loaded: None,
+            midi: MidiIn::new(),
+            patch_cache: PatchCache::new(track.bpm()),
+            voices: VoiceAllocator::new(patch.voices().clone()),
+            transport: Transport::new(clock, sample_rate),
+            track,
+            patch,
+            patch_name: None,
             sequence: Sequence::new(),
+            sequence_name: None,
             net,
             seq,
-            seq_events: HashMap::new(),
-            mode: Mode::Play
+            mode: Mode::Play,
+            sample_rate,
+            script_depth: 0,
+            tui: false,
         }
     }
 
-    pub fn run(mut self) -> anyhow::Result<()> {
+    pub fn run(&mut self) -> anyhow::Result<()> {
         let mut term = ratatui::init();
+        self.tui = true;
 
         let mut stdout = stdout();
         execute!(stdout, PushKeyboardEnhancementFlags(
             KeyboardEnhancementFlags::REPORT_EVENT_TYPES | KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES | KeyboardEnhancementFlags::REPORT_ALL_KEYS_AS_ESCAPE_CODES | KeyboardEnhancementFlags::REPORT_ALL_KEYS_AS_ESCAPE_CODES
         )).unwrap();
+        // for dragging in the widgets that take the mouse, like the envelope editor
+        execute!(stdout, EnableMouseCapture)?;
 
         loop {
+            self.seq_editor.set_playhead(self.playhead());
             term.draw(|f| {
                 self.draw(f);
             })?;
@@ -137,6 +252,15 @@ impl App {
                 break;
             }
 
+            // the controller plays whichever mode the UI is in
+            self.voices.update();
+            self.pads.update();
+            self.poll_midi();
+            if let Err(e) = self.transport.update(&mut self.seq, &self.track, &mut self.patch_cache, &mut self.rng) {
+                self.transport.stop(&mut self.seq);
+                self.cbox.push_error(format!("Stopped playback: {e}"));
+            }
+
             let should_stop = match self.mode {
                 Mode::Command => {
                     self.run_mode_command()
@@ -144,6 +268,15 @@ impl App {
                 Mode::Play => {
                     self.run_mode_play()
                 }
+                Mode::Pads => {
+                    self.run_mode_pads()
+                }
+                Mode::Patch => {
+                    self.run_mode_patch()
+                }
+                Mode::Sequence => {
+                    self.run_mode_sequence()
+                }
             }?;
             if should_stop {
                 break;
@@ -157,49 +290,358 @@ impl App {
     fn run_mode_command(&mut self) -> anyhow::Result<bool> {
         self.cbox.update_autocomplete();
 
-        if let Some(cmd) = self.cbox.get_command() {
-            // run command
-            match AppCommand::try_from(cmd.as_str()) {
+        if let Some(line) = self.cbox.get_command().filter(|c| !c.trim().is_empty()) {
+            match AppCommand::try_from(line.as_str()) {
+                Ok(cmd) => return self.run_command(cmd),
+                Err(e) => {
+                    self.cbox.push_error(e.pointer());
+                    self.cbox.push_error(format!("Error: {e}"));
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Runs the script at `path` without the TUI, printing what it outputs, then exits. Fails if
+    /// anything in the script did.
+    pub fn run_batch(&mut self, path: &str) -> anyhow::Result<()> {
+        self.run_script(path)?;
+        if self.cbox.flush_to_console() {
+            anyhow::bail!("script \"{path}\" failed");
+        }
+        Ok(())
+    }
+
+    /// Runs each line of the script at `path` as if entered in the command box, stopping at the first
+    /// that doesn't parse. Returns true if the script exits doris.
+    pub fn run_script(&mut self, path: &str) -> anyhow::Result<bool> {
+        if self.script_depth >= Self::MAX_SCRIPT_DEPTH {
+            self.cbox.push_error(format!("Not running \"{path}\": scripts are nested too deeply."));
+            return Ok(false);
+        }
+        let lines = match script::read_lines(path) {
+            Ok(lines) => lines,
+            Err(e) => {
+                self.cbox.push_error(format!("Failed to read script \"{path}\": {e}"));
+                return Ok(false);
+            }
+        };
+
+        self.script_depth += 1;
+        let mut rv = Ok(false);
+        for (n, line) in lines {
+            self.cbox.push_echo(line.clone());
+            match AppCommand::try_from(line.as_str()) {
                 Ok(cmd) => {
-                    match cmd {
-                        AppCommand::Exit => {
-                            return Ok(true);
-                        },
-                        AppCommand::LoadTrack(path) => {
-                            match Track::from_file(&path) {
-                                Ok(track) => {
-                                    self.track = track;
-                                    self.cbox.push_output(format!("Loaded track from \"{path}\"."));
-                                }
-                                Err(_) => {
-                                    self.cbox.push_error(format!("Failed to load track from \"{path}\"."));
-                                }
-                            }
-                        },
-                        AppCommand::LoadPatch(path) => {
-                            match Patch::from_file(&path) {
-                                Ok(patch) => {
-                                    self.patch = patch;
-                                    self.cbox.push_output(format!("Loaded patch from \"{path}\"."));
-                                }
-                                Err(_) => {
-                                    self.cbox.push_error(format!("Failed to load track from \"{path}\"."));
-                                }
-                            }
-                        },
-                        AppCommand::Play => {
-                            self.mode = Mode::Play;
+                    rv = self.run_command(cmd);
+                    if !matches!(rv, Ok(false)) {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    self.cbox.push_error(e.pointer());
+                    self.cbox.push_error(format!("{path}:{n}: Error: {e}"));
+                    break;
+                }
+            }
+        }
+        self.script_depth -= 1;
+        rv
+    }
+
+    /// Runs `cmd`, returning true if it exits doris.
+    fn run_command(&mut self, cmd: AppCommand) -> anyhow::Result<bool> {
+        match cmd {
+            AppCommand::Exit => {
+                return Ok(true);
+            },
+            AppCommand::Help(topic) => {
+                match command_line::help(&AppCommand::table(), &topic) {
+                    Ok(lines) => {
+                        for line in lines {
+                            self.cbox.push_output(line);
+                        }
+                    }
+                    Err(e) => self.cbox.push_error(format!("Error: {e}")),
+                }
+            },
+            AppCommand::Source(path) => {
+                if self.run_script(&path)? {
+                    return Ok(true);
+                }
+            },
+            AppCommand::LoadTrack(path) => {
+                match Track::from_file(&path) {
+                    Ok(track) => {
+                        self.transport.stop(&mut self.seq);
+                        self.patch_cache.clear();
+                        self.patch_cache.set_bpm(track.bpm());
+                        self.track = track;
+                        self.cbox.push_output(format!("Loaded track from \"{path}\"."));
+                    }
+                    Err(e) => {
+                        self.cbox.push_error(format!("Failed to load track from \"{path}\": {e}"));
+                    }
+                }
+            },
+            AppCommand::LoadPatch(path) => {
+                match Patch::from_file(&path) {
+                    Ok(patch) => {
+                        if self.set_live_patch(patch.clone(), None, &format!("patch from \"{path}\"")) {
+                            self.open_patch_editor(patch, None);
                         }
-                        _ => {
-                            self.cbox.push_error(format!("Unhandled cmd {cmd:?}"));
+                    }
+                    Err(e) => {
+                        self.cbox.push_error(format!("Failed to load patch from \"{path}\": {e}"));
+                    }
+                }
+            },
+            AppCommand::LoadSequence(path) => {
+                // the sequence joins the track under its file name
+                let name = Path::new(&path).file_stem().map(|s| s.to_string_lossy().into_owned());
+                match (Sequence::from_file(&path), name) {
+                    (Ok(sequence), Some(name)) => {
+                        if self.track.insert_sequence(&name, sequence.clone()).is_some() {
+                            self.cbox.push_output(format!("warning: replaced sequence \"{name}\" in the track"));
                         }
+                        self.cbox.push_output(format!("Loaded sequence \"{name}\" from \"{path}\"."));
+                        self.open_sequence_editor(sequence, name);
+                    }
+                    (Ok(_), None) => {
+                        self.cbox.push_error(format!("Cannot name a sequence after \"{path}\"."));
+                    }
+                    (Err(e), _) => {
+                        self.cbox.push_error(format!("Failed to load sequence from \"{path}\": {e}"));
+                    }
+                }
+            },
+            AppCommand::CreatePatch(name) => {
+                if self.track.patch(&name).is_some() {
+                    self.cbox.push_error(format!("The track already has a patch named \"{name}\"."));
+                }
+                else {
+                    let patch = Patch::basic();
+                    if self.set_live_patch(patch.clone(), Some(name.clone()), &format!("new patch \"{name}\"")) {
+                        self.track.insert_patch(&name, patch.clone());
+                        self.patch_cache.invalidate(&name);
+                        self.open_patch_editor(patch, Some(name.clone()));
+                    }
+                }
+            },
+            AppCommand::EditPatch(name) => {
+                match self.track.patch(&name) {
+                    Some(patch) => {
+                        let patch = patch.clone();
+                        // opened even with errors, so they can be fixed
+                        self.set_live_patch(patch.clone(), Some(name.clone()), &format!("patch \"{name}\""));
+                        self.open_patch_editor(patch, Some(name.clone()));
+                    }
+                    None => {
+                        self.cbox.push_error(format!("No patch named \"{name}\" in the track."));
+                    }
+                }
+            },
+            AppCommand::CreateSequence(name) => {
+                if self.track.sequence(&name).is_some() {
+                    self.cbox.push_error(format!("The track already has a sequence named \"{name}\"."));
+                }
+                else {
+                    self.track.insert_sequence(&name, Sequence::new());
+                    self.cbox.push_output(format!("Created sequence \"{name}\"."));
+                    self.open_sequence_editor(Sequence::new(), name);
+                }
+            },
+            AppCommand::EditSequence(name) => {
+                match self.track.sequence(&name) {
+                    Some(sequence) => {
+                        self.cbox.push_output(format!("Editing sequence \"{name}\"."));
+                        self.open_sequence_editor(sequence.clone(), name);
+                    }
+                    None => {
+                        self.cbox.push_error(format!("No sequence named \"{name}\" in the track."));
+                    }
+                }
+            },
+            AppCommand::Keys => {
+                self.mode = Mode::Play;
+            }
+            AppCommand::Pads => {
+                self.mode = Mode::Pads;
+            }
+            AppCommand::PadPatch(n, name) => {
+                let result = DrumPads::index(n).and_then(|pad| {
+                    if self.track.patch(&name).is_none() {
+                        anyhow::bail!("no patch named \"{name}\" in the track");
+                    }
+                    self.pads.bind(pad, PadSound::Patch(name.clone()));
+                    Ok(())
+                });
+                match result {
+                    Ok(()) => self.cbox.push_output(format!("Pad {n} plays patch \"{name}\".")),
+                    Err(e) => self.cbox.push_error(format!("Failed to set pad: {e}")),
+                }
+            }
+            AppCommand::PadSample(n, path) => {
+                let result = DrumPads::index(n).and_then(|pad| {
+                    // loading it now reports a bad file here rather than on the first hit
+                    self.patch_cache.get(&Self::pad_sample_key(&path), &Patch::sample(&path))?;
+                    self.pads.bind(pad, PadSound::Sample(path.clone()));
+                    Ok(())
+                });
+                match result {
+                    Ok(()) => self.cbox.push_output(format!("Pad {n} plays \"{path}\".")),
+                    Err(e) => self.cbox.push_error(format!("Failed to set pad: {e}")),
+                }
+            }
+            AppCommand::PadChoke(n, group) => {
+                let result = DrumPads::index(n).and_then(|pad| {
+                    if group < 0.0 || group.fract() != 0.0 {
+                        anyhow::bail!("choke groups are whole numbers, not {group}");
+                    }
+                    self.pads.set_choke(pad, (group > 0.0).then_some(group as usize));
+                    Ok(())
+                });
+                if let Err(e) = result {
+                    self.cbox.push_error(format!("Failed to set choke group: {e}"));
+                }
+            }
+            AppCommand::Play => {
+                if let Err(e) = self.transport.play() {
+                    self.cbox.push_error(format!("Failed to play: {e}"));
+                }
+            }
+            AppCommand::PlaySequence(name) => {
+                match self.track.sequence(&name) {
+                    Some(sequence) => {
+                        let length = sequence::bar_length(self.track.bpm());
+                        let notes = sequence.notes(self.track.bpm(), length);
+                        let sections = vec![Section { sequence: name.clone(), start: 0.0, length }];
+                        let result = self.transport.load(&mut self.seq, notes, sections, length)
+                            .and_then(|()| self.transport.play());
+                        match result {
+                            Ok(()) => {
+                                self.cbox.push_output(format!("Playing sequence \"{name}\"."));
+                                self.loaded = Some(Loaded::Sequence(name));
+                            },
+                            Err(e) => self.cbox.push_error(format!("Failed to play sequence \"{name}\": {e}")),
+                        }
+                    }
+                    None => {
+                        self.cbox.push_error(format!("No sequence named \"{name}\" in the track."));
+                    }
+                }
+            }
+            AppCommand::PlayTrack => {
+                let result = self.track.notes()
+                    .and_then(|notes| self.transport.load(&mut self.seq, notes, self.track.sections(), self.track.length()))
+                    .and_then(|()| self.transport.play());
+                match result {
+                    Ok(()) => {
+                        self.cbox.push_output("Playing track.".into());
+                        self.loaded = Some(Loaded::Track);
+                    },
+                    Err(e) => self.cbox.push_error(format!("Failed to play track: {e}")),
+                }
+            }
+            AppCommand::Pause => {
+                self.transport.pause(&mut self.seq);
+            }
+            AppCommand::Stop => {
+                self.transport.stop(&mut self.seq);
+            }
+            AppCommand::Seek(t) => {
+                if let Err(e) = self.transport.seek(&mut self.seq, t.seconds(self.track.bpm())) {
+                    self.cbox.push_error(format!("Failed to seek: {e}"));
+                }
+            }
+            AppCommand::Loop(looping) => {
+                self.transport.set_looping(&mut self.seq, looping);
+            }
+            AppCommand::LoopRegion(start, end) => {
+                let bpm = self.track.bpm();
+                if let Err(e) = self.transport.set_loop_region(&mut self.seq, start.seconds(bpm), end.seconds(bpm)) {
+                    self.cbox.push_error(format!("Failed to set loop region: {e}"));
+                }
+            }
+            AppCommand::SaveTrack(path) => {
+                match self.track.to_file(&path) {
+                    Ok(()) => self.cbox.push_output(format!("Saved track to \"{path}\".")),
+                    Err(e) => self.cbox.push_error(format!("Failed to save track to \"{path}\": {e}")),
+                }
+            }
+            AppCommand::SavePatch(path) => {
+                match self.patch.to_file(&path) {
+                    Ok(()) => self.cbox.push_output(format!("Saved patch to \"{path}\".")),
+                    Err(e) => self.cbox.push_error(format!("Failed to save patch to \"{path}\": {e}")),
+                }
+            }
+            AppCommand::SaveSequence(_) if self.sequence_name.is_none() => {
+                self.cbox.push_error("No sequence is being edited; create or edit one first.".into());
+            }
+            AppCommand::SaveSequence(path) => {
+                match self.sequence.to_file(&path) {
+                    Ok(()) => self.cbox.push_output(format!("Saved sequence to \"{path}\".")),
+                    Err(e) => self.cbox.push_error(format!("Failed to save sequence to \"{path}\": {e}")),
+                }
+            }
+            AppCommand::MidiList => {
+                match MidiIn::list_ports() {
+                    Ok(ports) if ports.is_empty() => {
+                        self.cbox.push_output("No MIDI input ports.".into());
+                    }
+                    Ok(ports) => {
+                        for (i, port) in ports.iter().enumerate() {
+                            self.cbox.push_output(format!("{i}: {port}"));
+                        }
+                    }
+                    Err(e) => {
+                        self.cbox.push_error(format!("Failed to list MIDI ports: {e}"));
+                    }
+                }
+            }
+            AppCommand::MidiConnect(port) => {
+                self.midi.disconnect();
+                match self.midi.connect(&port) {
+                    Ok(name) => {
+                        self.cbox.push_output(format!("Connected to MIDI port \"{name}\"."));
+                    }
+                    Err(e) => {
+                        self.cbox.push_error(format!("Failed to connect to MIDI port: {e}"));
+                    }
+                }
+            }
+            AppCommand::MidiVirtual => {
+                self.midi.disconnect();
+                match self.midi.connect_virtual(MIDI_VIRTUAL_PORT) {
+                    Ok(()) => {
+                        self.cbox.push_output(format!("Opened virtual MIDI port \"{MIDI_VIRTUAL_PORT}\"."));
+                    }
+                    Err(e) => {
+                        self.cbox.push_error(format!("Failed to open virtual MIDI port: {e}"));
+                    }
+                }
+            }
+            AppCommand::MidiDisconnect => {
+                match self.midi.disconnect() {
+                    Some(name) => self.cbox.push_output(format!("Disconnected from MIDI port \"{name}\".")),
+                    None => self.cbox.push_error("Not connected to a MIDI port.".into()),
+                }
+            }
+            AppCommand::Render(path) => {
+                match render::render_to_file(&self.track, &path, self.sample_rate, BitDepth::default()) {
+                    Ok(()) => {
+                        self.cbox.push_output(format!("Rendered track to \"{path}\"."));
+                    }
+                    Err(e) => {
+                        self.cbox.push_error(format!("Failed to render track: {e}"));
                     }
-                },
-                Err(m) => {
-                    self.cbox.push_error(format!("Error: {m}"));
                 }
             }
         }
+        // commands may have added to the track, or replaced it
+        self.cbox.set_names(self.track.patch_names().cloned().collect(), self.track.sequence_names().cloned().collect());
+        self.seq_editor.set_patches(self.track.patch_names().cloned().collect());
 
         Ok(false)
     }
@@ -211,45 +653,233 @@ impl App {
         }
 
         let events = self.kb.get_events();
-        // TODO
+        self.play_note_events(events);
+
+        Ok(false)
+    }
+
+    fn run_mode_pads(&mut self) -> anyhow::Result<bool> {
+        if self.pads.is_finished() {
+            self.mode = Mode::Command;
+            self.pads.set_unfinished();
+        }
+
+        for pad in self.pads.take_hits() {
+            if let Err(e) = self.play_pad(pad) {
+                self.cbox.push_error(format!("Failed to play pad {}: {e}", pad + 1));
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn run_mode_patch(&mut self) -> anyhow::Result<bool> {
+        if self.editor.is_finished() {
+            self.mode = Mode::Command;
+            self.editor.set_unfinished();
+        }
+
+        if let Some(patch) = self.editor.take_changed() {
+            self.apply_patch_edit(patch);
+        }
+
+        Ok(false)
+    }
+
+    fn run_mode_sequence(&mut self) -> anyhow::Result<bool> {
+        if self.seq_editor.is_finished() {
+            self.mode = Mode::Command;
+            self.seq_editor.set_unfinished();
+        }
+
+        if let Some(sequence) = self.seq_editor.take_changed() {
+            self.apply_sequence_edit(sequence);
+        }
+
+        Ok(false)
+    }
+
+    fn open_patch_editor(&mut self, patch: Patch, name: Option<String>) {
+        self.editor.set_patch(patch, name);
+        self.mode = Mode::Patch;
+        self.pane = Pane::Patch;
+    }
+
+    fn open_sequence_editor(&mut self, sequence: Sequence, name: String) {
+        self.sequence = sequence.clone();
+        self.sequence_name = Some(name.clone());
+        self.seq_editor.set_sequence(sequence, Some(name));
+        self.seq_editor.set_patches(self.track.patch_names().cloned().collect());
+        self.mode = Mode::Sequence;
+        self.pane = Pane::Sequence;
+    }
+
+    /// Stores an edited sequence back in the track, and has the transport play the edit if it is
+    /// playing the sequence.
+    fn apply_sequence_edit(&mut self, sequence: Sequence) {
+        let Some(name) = self.seq_editor.name().map(String::from)
+        else {
+            return;
+        };
+        self.track.insert_sequence(&name, sequence.clone());
+        self.sequence = sequence;
+        let bpm = self.track.bpm();
+        let notes = match &self.loaded {
+            Some(Loaded::Sequence(n)) if *n == name => Ok(self.sequence.notes(bpm, sequence::bar_length(bpm))),
+            Some(Loaded::Track) => self.track.notes(),
+            _ => return,
+        };
+        match notes {
+            Ok(notes) => self.transport.replace_notes(notes),
+            Err(e) => self.cbox.push_error(format!("Failed to update the transport: {e}")),
+        }
+    }
+
+    /// How far through the bar the transport is, while it plays the sequence being edited.
+    fn playhead(&self) -> Option<f64> {
+        if self.transport.state() != TransportState::Playing {
+            return None;
+        }
+        let (_, section) = self.transport.now_playing()?;
+        if Some(section.sequence.as_str()) != self.seq_editor.name() {
+            return None;
+        }
+        let bar = sequence::bar_length(self.track.bpm());
+        Some((self.transport.position() - section.start) % bar / bar)
+    }
+
+    /// Rebuilds the live patch after an edit in the editor, and stores it back in the track if it
+    /// came from there. Edits leaving errors are held back until they are fixed; the editor shows
+    /// them meanwhile.
+    fn apply_patch_edit(&mut self, patch: Patch) {
+        if patch.validate().iter().any(|d| d.is_error()) {
+            return;
+        }
+        if let Some(name) = self.editor.name() {
+            self.track.insert_patch(name, patch.clone());
+            self.patch_cache.invalidate(name);
+        }
+        self.patch = patch;
+        self.patch_name = self.editor.name().map(String::from);
+        self.patch_cache.invalidate(LIVE_PATCH);
+        self.voices.set_config(self.patch.voices().clone());
+        // notes already sounding keep the old net; the next ones get the new
+        if let Err(e) = self.patch_cache.get(LIVE_PATCH, &self.patch) {
+            self.cbox.push_error(format!("Failed to build the edited patch: {e}"));
+        }
+    }
+
+    /// Makes `patch` the one played from the keyboard, unless it has errors; `desc` names it in the
+    /// messages reporting its diagnostics. Returns whether it was loaded.
+    fn set_live_patch(&mut self, patch: Patch, name: Option<String>, desc: &str) -> bool {
+        let diagnostics = patch.validate();
+        let n_errors = diagnostics.iter().filter(|d| d.is_error()).count();
+        for d in diagnostics {
+            if d.is_error() {
+                self.cbox.push_error(format!("{d}"));
+            }
+            else {
+                self.cbox.push_output(format!("warning: {d}"));
+            }
+        }
+        if n_errors > 0 {
+            self.cbox.push_error(format!("The {desc} has {n_errors} error(s); not loaded."));
+            return false;
+        }
+
+        self.patch = patch;
+        self.patch_name = name;
+        self.patch_cache.invalidate(LIVE_PATCH);
+        self.voices.set_config(self.patch.voices().clone());
+        // build it now so the first note doesn't have to
+        match self.patch_cache.get(LIVE_PATCH, &self.patch) {
+            Ok(_) => self.cbox.push_output(format!("Loaded {desc}.")),
+            Err(e) => self.cbox.push_error(format!("Loaded {desc} but failed to build it: {e}")),
+        }
+        true
+    }
+
+    fn poll_midi(&mut self) {
+        let mut events = Vec::new();
+        for msg in self.midi.get_messages() {
+            match msg {
+                MidiMessage::Note(event) => events.push(event),
+                MidiMessage::PitchBend(bend) => self.voices.set_bend(bend),
+                MidiMessage::ModWheel(value) => self.voices.set_modulation(value),
+                MidiMessage::Aftertouch { key, pressure } => self.voices.set_pressure(key, pressure),
+                MidiMessage::Sustain(on) => {
+                    let release = self.patch.release_time() as f64;
+                    self.voices.set_sustain(&mut self.seq, on, release);
+                },
+            }
+        }
+        self.play_note_events(events);
+    }
+
+    fn play_note_events(&mut self, events: Vec<NoteEvent>) {
         for event in events {
-            match event {
-                NoteEvent { kind: NoteEventKind::Start, note, octave } => {
-                    let k = (note, octave);
-                    if !self.seq_events.contains_key(&k) {
-                        let f = note.to_freq_octave(octave);
-                        let pnet = self.patch.create_net().unwrap();
-                        let pnet = unit::<U2, U1>(Box::new(pnet));
-                        let mut unit = Box::new(
-                            (constant(f) | constant(1.0)) >> pnet
-                        );
-                        unit.ping(false, AttoHash::new(self.rng.u64()));
-                        let event_id = self.seq.push_relative(
-                            0.0,
-                            f64::INFINITY,
-                            Fade::Power,
-                            0.0,
-                            0.0,
-                            unit,
-                        );
-                        self.seq_events.insert(k, event_id);
-                    }
+            let key = (event.note, event.octave);
+            let result = match event.kind {
+                NoteEventKind::Start => {
+                    let f = event.note.to_freq_octave(event.octave);
+                    self.voices.note_on(&mut self.seq, key, f, event.velocity, |inputs, glide| Self::build_voice(
+                        &mut self.patch_cache, &self.patch, &mut self.rng, inputs, glide
+                    ))
                 },
-                NoteEvent { kind: NoteEventKind::Stop, note, octave } => {
-                    if let Some(event_id) = self.seq_events.remove(&(note, octave)) {
-                        self.seq.edit_relative(event_id, 0.0, 0.0);
-                    }
+                NoteEventKind::Stop => {
+                    let release = self.patch.release_time() as f64;
+                    self.voices.note_off(&mut self.seq, key, release, |inputs, glide| Self::build_voice(
+                        &mut self.patch_cache, &self.patch, &mut self.rng, inputs, glide
+                    ))
                 },
+            };
+            if let Err(e) = result {
+                self.cbox.push_error(format!("Failed to build patch: {e}"));
             }
         }
+    }
 
-        Ok(false)
+    /// Fires a drum pad's sound as a one-shot: its samples play through, or its patch's gate opens
+    /// briefly, then the envelopes release. Unbound pads are silent.
+    fn play_pad(&mut self, pad: usize) -> anyhow::Result<()> {
+        let (key, patch) = match self.pads.sound(pad) {
+            Some(PadSound::Patch(name)) => {
+                let patch = self.track.patch(name).ok_or_else(|| anyhow::anyhow!("no patch named \"{name}\" in the track"))?;
+                (name.clone(), patch.clone())
+            },
+            Some(PadSound::Sample(path)) => (Self::pad_sample_key(path), Patch::sample(path)),
+            None => return Ok(()),
+        };
+        let pnet = self.patch_cache.get(&key, &patch)?;
+        let samples = self.patch_cache.sample_length(&patch)?;
+        let gate = if samples > 0.0 { samples } else { PAD_GATE };
+        let mut unit = voice::timed_note(pnet, Note::A.to_freq_octave(4), gate);
+        unit.ping(false, AttoHash::new(self.rng.u64()));
+        let length = gate + patch.release_time() as f64;
+        self.pads.trigger(&mut self.seq, pad, unit, length);
+        Ok(())
+    }
+
+    /// Cache key of the patch playing a drum pad's WAV file.
+    fn pad_sample_key(path: &str) -> String {
+        format!("<pad {path}>")
+    }
+
+    /// Creates the unit for one voice of the live patch.
+    fn build_voice(cache: &mut PatchCache, patch: &Patch, rng: &mut Rnd, inputs: &VoiceInputs, glide: Option<f32>) -> anyhow::Result<Box<dyn AudioUnit>> {
+        let pnet = cache.get(LIVE_PATCH, patch)?;
+        let mut unit = voice::live_note(pnet, inputs, glide);
+        unit.ping(false, AttoHash::new(rng.u64()));
+        Ok(unit)
     }
 
     fn selected<'a>(&'a mut self) -> &'a mut dyn EventHandler {
         match self.mode {
             Mode::Command => &mut self.cbox,
             Mode::Play => &mut self.kb,
+            Mode::Pads => &mut self.pads,
+            Mode::Patch => &mut self.editor,
+            Mode::Sequence => &mut self.seq_editor,
         }
     }
 
@@ -265,12 +895,6 @@ impl App {
                         KeyEvent { code: KeyCode::Char('c'), modifiers: KeyModifiers::CONTROL, kind: KeyEventKind::Press, ..} => {
                             Ok(true)
                         },
-                        KeyEvent { code: KeyCode::Up, kind: KeyEventKind::Press, ..} => {
-                            Ok(false)
-                        },
-                        KeyEvent { code: KeyCode::Down, kind: KeyEventKind::Press, ..} => {
-                            Ok(false)
-                        },
                         kev => selected.handle_key(kev)
                     }
                 },
@@ -286,8 +910,11 @@ impl App {
 
 impl Drop for App {
     fn drop(&mut self) {
+        if !self.tui {
+            return;
+        }
         let mut stdout = stdout();
-        let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+        let _ = execute!(stdout, PopKeyboardEnhancementFlags, DisableMouseCapture);
         ratatui::restore();
     }
 }
@@ -295,7 +922,7 @@ impl Drop for App {
 
 impl FrameRenderable for App {
     fn draw_into(&self, frame: &mut Frame, area: Rect) {
-        let [workspace, _, bottom] = Layout::new(Direction::Vertical, vec![
+        let [workspace, status, bottom] = Layout::new(Direction::Vertical, vec![
                 Constraint::Min(50),
                 Constraint::Length(1),
                 Constraint::Min(25),
@@ -311,9 +938,15 @@ impl FrameRenderable for App {
         // let tabs = Tabs::new(vec!["1/Patch", "2/Sequence", "3/Play"]);
         // tabs.render(tab_area, frame.buffer_mut());
 
+        match self.pane {
+            Pane::Patch => self.editor.draw_into(frame, workspace),
+            Pane::Sequence => self.seq_editor.draw_into(frame, workspace),
+        }
+        self.transport.draw_into(frame, status);
         match self.mode {
-            Mode::Command => { self.cbox.draw_into(frame, bottom); },
+            Mode::Command | Mode::Patch | Mode::Sequence => { self.cbox.draw_into(frame, bottom); },
             Mode::Play => { self.kb.draw_into(frame, bottom); }
+            Mode::Pads => { self.pads.draw_into(frame, bottom); }
         }
     }
 }
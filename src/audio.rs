@@ -0,0 +1,195 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SizedSample};
+
+use crate::render::{BitDepth, WavSink};
+
+
+/// Source of stereo frames handed to a backend; called once per frame.
+pub type FrameSource = Box<dyn FnMut() -> (f32, f32) + Send>;
+
+pub trait AudioBackend {
+    fn sample_rate(&self) -> f64;
+
+    /// Starts pulling frames from `next_frame`. Audio keeps running until the backend is dropped.
+    fn start(&mut self, next_frame: FrameSource) -> anyhow::Result<()>;
+}
+
+
+/// Which backend to use, as chosen on the command line.
+pub enum BackendKind {
+    Cpal,
+    /// `length` is how many seconds of audio to pull before stopping, if not until exit.
+    Null { sample_rate: f64, realtime: bool, sink: Option<String>, length: Option<f64> },
+}
+
+impl BackendKind {
+    pub fn create(self) -> anyhow::Result<Box<dyn AudioBackend>> {
+        let rv: Box<dyn AudioBackend> = match self {
+            Self::Cpal => Box::new(CpalBackend::new()?),
+            Self::Null { sample_rate, realtime, sink, length } => Box::new(NullBackend::new(sample_rate, realtime, sink, length)),
+        };
+        Ok(rv)
+    }
+}
+
+
+/// Plays through the default output device of the default cpal host.
+pub struct CpalBackend {
+    device: cpal::Device,
+    config: cpal::SupportedStreamConfig,
+    stream: Option<cpal::Stream>,
+}
+
+impl CpalBackend {
+    pub fn new() -> anyhow::Result<Self> {
+        let host = cpal::default_host();
+        let device = host
+            .default_output_device()
+            .ok_or_else(|| anyhow!("failed to find a default output device (try --audio null)"))?;
+        let config = device.default_output_config()?;
+        Ok(Self { device, config, stream: None })
+    }
+
+    fn build_stream<T>(&self, mut next_frame: FrameSource) -> anyhow::Result<cpal::Stream>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let config: cpal::StreamConfig = self.config.clone().into();
+        let channels = config.channels as usize;
+        let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
+
+        let stream = self.device.build_output_stream(
+            &config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                write_data(data, channels, &mut next_frame)
+            },
+            err_fn,
+            None,
+        )?;
+        Ok(stream)
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn sample_rate(&self) -> f64 {
+        self.config.sample_rate().0 as f64
+    }
+
+    fn start(&mut self, next_frame: FrameSource) -> anyhow::Result<()> {
+        let stream = match self.config.sample_format() {
+            cpal::SampleFormat::F32 => self.build_stream::<f32>(next_frame)?,
+            cpal::SampleFormat::I16 => self.build_stream::<i16>(next_frame)?,
+            cpal::SampleFormat::U16 => self.build_stream::<u16>(next_frame)?,
+            f => bail!("unsupported sample format {f}"),
+        };
+        stream.play()?;
+        self.stream = Some(stream);
+        Ok(())
+    }
+}
+
+fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> (f32, f32))
+where
+    T: SizedSample + FromSample<f32>,
+{
+    for frame in output.chunks_mut(channels) {
+        let sample = next_sample();
+        let left = T::from_sample(sample.0);
+        let right: T = T::from_sample(sample.1);
+
+        for (channel, sample) in frame.iter_mut().enumerate() {
+            if channel & 1 == 0 {
+                *sample = left;
+            } else {
+                *sample = right;
+            }
+        }
+    }
+}
+
+
+/// Pulls frames on a background thread without an output device, either paced to real time or as
+/// fast as possible, optionally recording them to a WAV file and stopping after a set length.
+pub struct NullBackend {
+    sample_rate: f64,
+    realtime: bool,
+    sink: Option<String>,
+    length: Option<f64>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl NullBackend {
+    const BLOCK_SIZE: usize = 256;
+
+    pub fn new(sample_rate: f64, realtime: bool, sink: Option<String>, length: Option<f64>) -> Self {
+        Self { sample_rate, realtime, sink, length, stop: Arc::new(AtomicBool::new(false)), thread: None }
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    fn start(&mut self, mut next_frame: FrameSource) -> anyhow::Result<()> {
+        let mut sink = match &self.sink {
+            Some(path) => Some(WavSink::create(path, self.sample_rate, BitDepth::Float32)?),
+            None => None,
+        };
+        let sample_rate = self.sample_rate;
+        let realtime = self.realtime;
+        let limit = self.length.map(|l| (l * sample_rate).round() as usize);
+        let stop = self.stop.clone();
+
+        self.thread = Some(std::thread::spawn(move || {
+            let t0 = Instant::now();
+            let mut frames = 0usize;
+            while !stop.load(Ordering::Relaxed) && limit.is_none_or(|l| frames < l) {
+                let block = limit.map_or(Self::BLOCK_SIZE, |l| Self::BLOCK_SIZE.min(l - frames));
+                for _ in 0..block {
+                    let (left, right) = next_frame();
+                    if let Some(sink) = sink.as_mut() {
+                        sink.write_frame(left, right)?;
+                    }
+                }
+                frames += block;
+
+                if realtime {
+                    let due = t0 + Duration::from_secs_f64(frames as f64 / sample_rate);
+                    let now = Instant::now();
+                    if due > now {
+                        std::thread::sleep(due - now);
+                    }
+                }
+            }
+            match sink {
+                Some(sink) => sink.finalize(),
+                None => Ok(()),
+            }
+        }));
+        Ok(())
+    }
+}
+
+impl Drop for NullBackend {
+    fn drop(&mut self) {
+        // running fast to a set length, it finishes the recording first, which takes no time to speak of
+        if self.realtime || self.length.is_none() {
+            self.stop.store(true, Ordering::Relaxed);
+        }
+        if let Some(thread) = self.thread.take() {
+            match thread.join() {
+                Ok(Err(e)) => eprintln!("null audio backend failed: {e}"),
+                Err(_) => eprintln!("null audio backend panicked"),
+                Ok(Ok(())) => (),
+            }
+        }
+    }
+}
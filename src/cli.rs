@@ -0,0 +1,178 @@
+use anyhow::{anyhow, bail};
+
+use crate::audio::BackendKind;
+use crate::render::BitDepth;
+
+
+pub const USAGE: &str = "\
+usage:
+    doris [--audio cpal|null] [--fast] [--sink <out.wav>] [--rate <hz>] [--length <seconds>] [--script <file>]
+    doris render <track.yaml> <out.wav> [--rate <hz>] [--bits 16|24|32]
+
+--fast and --sink choose the null backend, which plays to no device; --rate and
+--length apply to it alone. --length stops it after that many seconds; it must be
+given with --fast --sink, and then the recording always runs to that length.
+--script runs the commands in <file> without the TUI, then exits. Otherwise
+~/.config/doris/dorisrc is run at startup, if it exists.";
+
+pub enum CliCommand {
+    Interactive {
+        audio: BackendKind,
+        /// Commands to run in place of the TUI.
+        script: Option<String>,
+    },
+    Render {
+        track: String,
+        output: String,
+        sample_rate: f64,
+        depth: BitDepth,
+    },
+}
+
+impl CliCommand {
+    pub fn from_args(args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut args = args.peekable();
+        match args.peek().map(|s| s.as_str()) {
+            None => Self::interactive_from_args(args),
+            Some(s) if s.starts_with("--") => Self::interactive_from_args(args),
+            Some("render") => {
+                let _ = args.next();
+                let mut positional = Vec::new();
+                let mut sample_rate = 44100.0;
+                let mut depth = BitDepth::default();
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "--rate" => {
+                            let v = args.next().ok_or_else(|| anyhow!("--rate needs a value"))?;
+                            sample_rate = v.parse().map_err(|_| anyhow!("invalid sample rate \"{v}\""))?;
+                        },
+                        "--bits" => {
+                            let v = args.next().ok_or_else(|| anyhow!("--bits needs a value"))?;
+                            depth = v.parse()?;
+                        },
+                        _ => positional.push(arg),
+                    }
+                }
+                match <[String; 2]>::try_from(positional) {
+                    Ok([track, output]) => Ok(Self::Render { track, output, sample_rate, depth }),
+                    Err(_) => bail!("render expects a track and an output path"),
+                }
+            },
+            Some(other) => bail!("unrecognised command \"{other}\""),
+        }
+    }
+
+    fn interactive_from_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut cpal = None;
+        let mut fast = false;
+        let mut sink = None;
+        let mut sample_rate = None;
+        let mut length = None;
+        let mut script = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--audio" => {
+                    let v = args.next().ok_or_else(|| anyhow!("--audio needs a value"))?;
+                    cpal = match v.as_str() {
+                        "cpal" => Some(true),
+                        "null" => Some(false),
+                        _ => bail!("unknown audio backend \"{v}\" (expected cpal or null)"),
+                    };
+                },
+                "--fast" => fast = true,
+                "--sink" => {
+                    sink = Some(args.next().ok_or_else(|| anyhow!("--sink needs a path"))?);
+                },
+                "--rate" => {
+                    let v = args.next().ok_or_else(|| anyhow!("--rate needs a value"))?;
+                    sample_rate = Some(v.parse().map_err(|_| anyhow!("invalid sample rate \"{v}\""))?);
+                },
+                "--length" => {
+                    let v = args.next().ok_or_else(|| anyhow!("--length needs a value"))?;
+                    length = Some(v.parse::<f64>().ok().filter(|l| *l > 0.0).ok_or_else(|| anyhow!("invalid length \"{v}\""))?);
+                },
+                "--script" => {
+                    script = Some(args.next().ok_or_else(|| anyhow!("--script needs a path"))?);
+                },
+                _ => bail!("unexpected argument \"{arg}\""),
+            }
+        }
+
+        // these only mean anything to the null backend, and the first two pick it by default
+        let null_only = [
+            ("--fast", fast),
+            ("--sink", sink.is_some()),
+            ("--rate", sample_rate.is_some()),
+            ("--length", length.is_some()),
+        ];
+        let cpal = cpal.unwrap_or(!(fast || sink.is_some()));
+        if cpal && let Some((flag, _)) = null_only.iter().find(|(_, given)| *given) {
+            bail!("{flag} only applies to the null audio backend; cpal plays at the device's rate, in real time");
+        }
+        if fast && sink.is_some() && length.is_none() {
+            bail!("--fast with --sink needs a --length, or the recording grows without end");
+        }
+
+        let audio = if cpal {
+            BackendKind::Cpal
+        }
+        else {
+            BackendKind::Null { sample_rate: sample_rate.unwrap_or(44100.0), realtime: !fast, sink, length }
+        };
+        Ok(Self::Interactive { audio, script })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> anyhow::Result<CliCommand> {
+        CliCommand::from_args(args.split_whitespace().map(String::from))
+    }
+
+    fn error(args: &str) -> String {
+        parse(args).err().map(|e| e.to_string()).unwrap_or_default()
+    }
+
+    #[test]
+    fn picks_the_audio_backend() {
+        assert!(matches!(parse(""), Ok(CliCommand::Interactive { audio: BackendKind::Cpal, script: None })));
+        assert!(matches!(
+            parse("--audio null --rate 48000"),
+            Ok(CliCommand::Interactive { audio: BackendKind::Null { sample_rate: 48000.0, realtime: true, sink: None, length: None }, .. })
+        ));
+        assert!(matches!(
+            parse("--fast --script s.txt"),
+            Ok(CliCommand::Interactive { audio: BackendKind::Null { sample_rate: 44100.0, realtime: false, .. }, script: Some(_) })
+        ));
+        let Ok(CliCommand::Interactive { audio: BackendKind::Null { realtime, sink, length, .. }, .. }) = parse("--fast --sink out.wav --length 2.5") else {
+            panic!("expected the null backend");
+        };
+        assert_eq!((realtime, sink.as_deref(), length), (false, Some("out.wav"), Some(2.5)));
+    }
+
+    #[test]
+    fn reports_conflicting_options() {
+        assert!(error("--audio cpal --fast").starts_with("--fast only applies to the null audio backend"));
+        assert!(error("--sink out.wav --audio cpal").starts_with("--sink only applies"));
+        assert!(error("--rate 48000").starts_with("--rate only applies"));
+        assert!(error("--length 3").starts_with("--length only applies"));
+        assert!(error("--fast --sink out.wav").starts_with("--fast with --sink needs a --length"));
+        assert_eq!(error("--audio null --length 0"), "invalid length \"0\"");
+        assert_eq!(error("--audio alsa"), "unknown audio backend \"alsa\" (expected cpal or null)");
+    }
+
+    #[test]
+    fn parses_render() {
+        let Ok(CliCommand::Render { track, output, sample_rate, depth }) = parse("render t.yaml --bits 24 out.wav") else {
+            panic!("expected render");
+        };
+        assert_eq!((track.as_str(), output.as_str(), sample_rate), ("t.yaml", "out.wav", 44100.0));
+        assert!(matches!(depth, BitDepth::Int24));
+        assert!(matches!(parse("render t.yaml out.wav"), Ok(CliCommand::Render { depth: BitDepth::Int16, .. })));
+        assert_eq!(error("render t.yaml"), "render expects a track and an output path");
+        assert_eq!(error("render t.yaml out.wav --bits 8"), "unsupported bit depth \"8\" (expected 16, 24 or 32)");
+    }
+}
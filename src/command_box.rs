@@ -1,22 +1,35 @@
 use std::collections::VecDeque;
 
-use crossterm::event::{KeyCode, KeyEvent, KeyEventKind};
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
 use ratatui::widgets::{Block, Borders, Paragraph, Widget};
 use ratatui::prelude::*;
 
-use crate::app::Arg;
+use crate::command_line::Arg;
+use crate::completion;
 use crate::event_handler::EventHandler;
 use crate::frame_renderable::FrameRenderable;
+use crate::history::History;
 
 enum HistoryType {
     Output, Echo, Error
 }
 
 pub struct CommandBox {
-    ac: Vec<(String, Arg)>,
+    ac: Vec<(String, Vec<Arg>)>,
     ac_suggestions: Vec<(String, String)>,
     ac_selection: usize,
+    /// The input the suggestions were made for.
+    ac_buf: Option<String>,
+    patch_names: Vec<String>,
+    sequence_names: Vec<String>,
     history: VecDeque<(HistoryType, String)>,
+    /// Commands entered, for recall.
+    recall: History,
+    /// The entry shown while stepping through `recall`, and the line that was being typed before.
+    recall_index: Option<usize>,
+    draft: String,
+    /// Reverse search query, and the entry it found.
+    search: Option<(String, Option<usize>)>,
     buf: String,
     ready: bool,
     cursor_position: usize,
@@ -28,7 +41,14 @@ impl CommandBox {
             ac: Vec::new(),
             ac_suggestions: Vec::new(),
             ac_selection: 0,
+            ac_buf: None,
+            patch_names: Vec::new(),
+            sequence_names: Vec::new(),
             history: VecDeque::new(),
+            recall: History::new(),
+            recall_index: None,
+            draft: String::new(),
+            search: None,
             buf: String::new(),
             ready: false,
             cursor_position: 0,
@@ -55,8 +75,119 @@ impl CommandBox {
         self.push(HistoryType::Error, error);
     }
 
+    /// Shows `line` as if it had been entered.
+    pub fn push_echo(&mut self, line: String) {
+        self.push(HistoryType::Echo, line);
+    }
+
     fn echo(&mut self) {
-        self.push(HistoryType::Echo, self.buf.clone());
+        self.push_echo(self.buf.clone());
+    }
+
+    /// Prints everything shown so far to stdout, errors to stderr, and clears it. Returns true if
+    /// there were errors.
+    pub fn flush_to_console(&mut self) -> bool {
+        let mut errors = false;
+        for (ht, s) in self.history.drain(..) {
+            match ht {
+                HistoryType::Echo => println!(">> {s}"),
+                HistoryType::Output => println!("   {s}"),
+                HistoryType::Error => {
+                    eprintln!("!  {s}");
+                    errors = true;
+                },
+            }
+        }
+        errors
+    }
+
+    /// Sets the commands that Up, Down and Ctrl-R recall, which entered commands are added to.
+    pub fn set_history(&mut self, history: History) {
+        self.recall = history;
+        self.recall_index = None;
+    }
+
+    fn enter(&mut self) {
+        self.echo();
+        if let Err(e) = self.recall.push(&self.buf) {
+            self.push_error(format!("Failed to save history: {e}"));
+        }
+        self.recall_index = None;
+        self.ready = true;
+    }
+
+    fn set_buf(&mut self, s: String) {
+        self.buf = s;
+        self.cursor_position = self.buf.len();
+    }
+
+    fn recall_prev(&mut self) {
+        let n = self.recall.entries().len();
+        let i = match self.recall_index {
+            Some(i) => i.saturating_sub(1),
+            None if n > 0 => {
+                self.draft = self.buf.clone();
+                n - 1
+            },
+            None => return,
+        };
+        self.recall_index = Some(i);
+        self.set_buf(self.recall.entries()[i].clone());
+    }
+
+    fn recall_next(&mut self) {
+        match self.recall_index {
+            Some(i) if i + 1 < self.recall.entries().len() => {
+                self.recall_index = Some(i + 1);
+                self.set_buf(self.recall.entries()[i + 1].clone());
+            },
+            Some(_) => {
+                self.recall_index = None;
+                let draft = std::mem::take(&mut self.draft);
+                self.set_buf(draft);
+            },
+            None => (),
+        }
+    }
+
+    fn handle_search_key(&mut self, kev: KeyEvent) {
+        let Some((query, found)) = self.search.as_mut()
+        else {
+            return;
+        };
+        if kev.kind != KeyEventKind::Press {
+            return;
+        }
+        let n = self.recall.entries().len();
+        match kev {
+            KeyEvent { code: KeyCode::Char('r'), modifiers: KeyModifiers::CONTROL, .. } => {
+                // look further back, staying put if there is nothing older
+                if let Some(i) = self.recall.search(query, found.unwrap_or(n)) {
+                    *found = Some(i);
+                }
+            },
+            KeyEvent { code: KeyCode::Char(c), .. } => {
+                query.push(c);
+                *found = self.recall.search(query, found.map_or(n, |i| i + 1));
+            },
+            KeyEvent { code: KeyCode::Backspace, .. } => {
+                query.pop();
+                *found = self.recall.search(query, n);
+            },
+            KeyEvent { code: KeyCode::Esc, .. } => {
+                self.search = None;
+            },
+            KeyEvent { code, .. } => {
+                // anything else takes the entry found to the line, and Enter runs it
+                if let Some(i) = *found {
+                    self.set_buf(self.recall.entries()[i].clone());
+                }
+                self.search = None;
+                if code == KeyCode::Enter {
+                    self.enter();
+                }
+            },
+        }
     }
 
     fn push(&mut self, ht: HistoryType, s: String) {
@@ -66,34 +197,77 @@ impl CommandBox {
         }
     }
 
-    pub fn set_autocomplete(&mut self, options: Vec<(String, Arg)>) {
+    pub fn set_autocomplete(&mut self, options: Vec<(String, Vec<Arg>)>) {
         self.ac = options;
+        self.ac_buf = None;
     }
 
     pub fn update_autocomplete(&mut self) {
+        if self.ac_buf.as_ref() == Some(&self.buf) {
+            return;
+        }
         let mut suggestions = Vec::new();
-        for (stem, arg) in self.ac.iter() {
-            if self.buf.starts_with(stem) || (&self.buf == stem) {
+        for (stem, args) in self.ac.iter() {
+            let placeholder: Vec<_> = args.iter().map(|a| a.placeholder()).collect();
+            let placeholder = placeholder.join(" ");
+            if let Some(rest) = self.buf.strip_prefix(stem.as_str()).and_then(|r| r.strip_prefix(' ')) {
                 // complete command, suggest values for args
-                // TODO
-                match arg {
-                    Arg::None => { },
-                    Arg::NewPatchName|Arg::SequenceName|Arg::PatchName|Arg::NewSequenceName => { suggestions.push((format!("{stem} "), format!("$name"))) },
-                    Arg::Path(patt) => {suggestions.push((format!("{stem} "), format!("$path/{patt}"))) }
-                    _ => { /*TODO*/ }
+                let (partial, quote) = match args.first() {
+                    Some(Arg::Command) => (rest.to_string(), None),
+                    _ => completion::partial_word(rest),
+                };
+                let candidates = match args.first() {
+                    Some(Arg::Path(glob)) => completion::complete_path(&partial, glob),
+                    Some(Arg::PatchName) => Self::complete_name(&self.patch_names, &partial),
+                    Some(Arg::SequenceName) => Self::complete_name(&self.sequence_names, &partial),
+                    Some(Arg::NewPatchName) if self.patch_names.contains(&partial) => {
+                        suggestions.push((self.buf.clone(), format!("  (patch \"{partial}\" already exists)")));
+                        Vec::new()
+                    },
+                    Some(Arg::NewSequenceName) if self.sequence_names.contains(&partial) => {
+                        suggestions.push((self.buf.clone(), format!("  (sequence \"{partial}\" already exists)")));
+                        Vec::new()
+                    },
+                    Some(Arg::Command) => Self::complete_name(self.ac.iter().map(|(stem, _)| stem), &partial),
+                    _ => Vec::new(),
+                };
+                for candidate in candidates {
+                    let suffix = completion::escape(&candidate[partial.len()..], quote);
+                    suggestions.push((format!("{}{suffix}", self.buf), String::new()));
+                }
+                if suggestions.is_empty() && rest.trim().is_empty() && !args.is_empty() {
+                    suggestions.push((format!("{stem} "), placeholder));
+                }
+            }
+            else if self.buf == *stem {
+                if !args.is_empty() {
+                    suggestions.push((format!("{stem} "), placeholder));
                 }
             }
             else if stem.starts_with(&self.buf) {
                 // incomplete command, suggest commands + arg proto
-                match arg {
-                    Arg::None => { suggestions.push((stem.clone(), String::new())) },
-                    Arg::NewPatchName|Arg::SequenceName|Arg::PatchName|Arg::NewSequenceName => { suggestions.push((format!("{stem} "), format!("$name"))) },
-                    Arg::Path(patt) => {suggestions.push((format!("{stem} "), format!("$path/{patt}"))) }
-                    _ => { /*TODO*/ }
+                if args.is_empty() {
+                    suggestions.push((stem.clone(), String::new()));
+                }
+                else {
+                    suggestions.push((format!("{stem} "), placeholder));
                 }
             }
         }
         self.ac_suggestions = suggestions;
+        self.ac_selection = 0;
+        self.ac_buf = Some(self.buf.clone());
+    }
+
+    /// Sets the names that patch and sequence arguments are completed from.
+    pub fn set_names(&mut self, patches: Vec<String>, sequences: Vec<String>) {
+        self.patch_names = patches;
+        self.sequence_names = sequences;
+        self.ac_buf = None;
+    }
+
+    fn complete_name<'a>(names: impl IntoIterator<Item = &'a String>, partial: &str) -> Vec<String> {
+        names.into_iter().filter(|n| n.starts_with(partial)).cloned().collect()
     }
 
     fn ac_next(&mut self) {
@@ -111,15 +285,25 @@ impl CommandBox {
     }
 
     fn ac_select(&mut self) {
-        self.buf = self.ac_suggestions[self.ac_selection].0.clone();
-        self.cursor_position = self.buf.len();
+        if let Some((s, _)) = self.ac_suggestions.get(self.ac_selection) {
+            self.buf = s.clone();
+            self.cursor_position = self.buf.len();
+        }
     }
 }
 
 
 impl EventHandler for CommandBox {
     fn handle_key(&mut self, kev: KeyEvent) -> anyhow::Result<bool> {
+        if self.search.is_some() {
+            self.handle_search_key(kev);
+            return Ok(false);
+        }
+
         match kev {
+            KeyEvent { code: KeyCode::Char('r'), modifiers: KeyModifiers::CONTROL, kind: KeyEventKind::Press, .. } => {
+                self.search = Some((String::new(), self.recall.entries().len().checked_sub(1)));
+            },
             KeyEvent { code: KeyCode::Char(c), kind: KeyEventKind::Press, .. } => {
                 if self.cursor_position == self.buf.len() {
                     self.buf.push(c);
@@ -130,12 +314,18 @@ impl EventHandler for CommandBox {
                 self.cursor_position += 1;
             },
             KeyEvent { code: KeyCode::Enter, kind: KeyEventKind::Press, .. } => {
-                self.echo();
-                self.ready = true;
+                self.enter();
             },
             KeyEvent { code: KeyCode::Esc, kind: KeyEventKind::Press, .. } => {
                 self.buf = String::new();
                 self.cursor_position = 0;
+                self.recall_index = None;
+            },
+            KeyEvent { code: KeyCode::Up, kind: KeyEventKind::Press, .. } => {
+                self.recall_prev();
+            },
+            KeyEvent { code: KeyCode::Down, kind: KeyEventKind::Press, .. } => {
+                self.recall_next();
             },
             KeyEvent { code: KeyCode::Left, kind: KeyEventKind::Press, .. } => {
                 self.cursor_position = self.cursor_position.saturating_sub(1);
@@ -187,7 +377,7 @@ impl FrameRenderable for CommandBox {
             .borders(Borders::ALL)
             .dim()
             .title_bottom(
-                Line::from("Tab for options; right arrow to select; enter to run.")
+                Line::from("Tab for options; right arrow to select; enter to run; up/down or ctrl-r for history.")
                 .centered()
             );
         let inner = block.inner(area);
@@ -230,9 +420,17 @@ impl FrameRenderable for CommandBox {
             String::new()
         };
 
+        let (text, ac, cursor) = match &self.search {
+            Some((query, found)) => {
+                let entry = found.map_or("", |i| self.recall.entries()[i].as_str());
+                let label = if found.is_some() { "reverse search" } else { "failing reverse search" };
+                (entry, format!("  ({label}: {query})"), entry.chars().count())
+            },
+            None => (self.buf.as_str(), ac, self.cursor_position),
+        };
         let line = Line::from(vec![
             Span::styled(">> ", Style::new().dim()),
-            Span::raw(&self.buf),
+            Span::raw(text),
             Span::styled(ac, Style::new().dark_gray()),
         ]);
 
@@ -241,7 +439,7 @@ impl FrameRenderable for CommandBox {
         let para = Paragraph::new(lines);
         para.render(area, frame.buffer_mut());
 
-        let cx = area.x + 3 + (self.cursor_position as u16);
+        let cx = area.x + 3 + (cursor as u16);
         let cy = area.y + area.height - 1;
         frame.set_cursor_position((cx, cy));
     }
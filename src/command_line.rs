@@ -0,0 +1,500 @@
+use std::fmt;
+
+use crate::completion;
+use crate::keyboard::Note;
+use crate::sequence;
+
+
+/// Kinds of argument a command takes, which decide how it is parsed and completed.
+#[derive(Debug, Clone)]
+pub enum Arg {
+    /// A file path, completed from files matching the glob; a leading `~` is the home directory.
+    Path(&'static str),
+    PatchName,
+    NewPatchName,
+    SequenceName,
+    NewSequenceName,
+    /// The rest of the line, as port names often have spaces in them.
+    MidiPort,
+    Number,
+    Time,
+    /// A note name and octave, e.g. `C4`; no command takes one yet.
+    #[allow(dead_code)]
+    Note,
+    /// The rest of the line, naming a command or the first words of some; may be empty. Only valid as
+    /// a command's sole argument.
+    Command,
+}
+
+impl Arg {
+    /// How the argument is shown where it is still to be typed.
+    pub fn placeholder(&self) -> String {
+        match self {
+            Self::Path(glob) => format!("$path/{glob}"),
+            Self::PatchName | Self::NewPatchName | Self::SequenceName | Self::NewSequenceName => "$name".into(),
+            Self::MidiPort => "$port".into(),
+            Self::Number => "$number".into(),
+            Self::Time => "$time".into(),
+            Self::Note => "$note".into(),
+            Self::Command => "[$command]".into(),
+        }
+    }
+
+    /// What the argument is, for help.
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Path(glob) => format!("path to a file matching {glob}; ~ is your home directory"),
+            Self::PatchName => "name of a patch in the track".into(),
+            Self::NewPatchName => "name for a new patch".into(),
+            Self::SequenceName => "name of a sequence in the track".into(),
+            Self::NewSequenceName => "name for a new sequence".into(),
+            Self::MidiPort => "MIDI input port, by (part of) its name or its number".into(),
+            Self::Number => "a number".into(),
+            Self::Time => "seconds (1.5, 1.5s, 250ms), beats (2b) or bars (1bar)".into(),
+            Self::Note => "note name and octave, e.g. C4, F#3 or Bb2".into(),
+            Self::Command => "a command, or its first words".into(),
+        }
+    }
+
+    fn parse(&self, text: &str) -> Result<ArgValue, String> {
+        match self {
+            Self::Number => text.parse()
+                .map(ArgValue::Number)
+                .map_err(|_| format!("\"{text}\" is not a number")),
+            Self::Time => Time::parse(text)
+                .map(ArgValue::Time)
+                .ok_or_else(|| format!("\"{text}\" is not a time (e.g. 1.5, 1.5s, 250ms, 2b or 1bar)")),
+            Self::Note => parse_note(text)
+                .map(|(note, octave)| ArgValue::Note(note, octave))
+                .ok_or_else(|| format!("\"{text}\" is not a note (e.g. C4, F#3 or Bb2)")),
+            Self::Path(_) => Ok(ArgValue::Text(completion::expand_home(text))),
+            _ => Ok(ArgValue::Text(text.to_string())),
+        }
+    }
+}
+
+
+/// A length of time, in seconds or in musical units that depend on the tempo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Time {
+    Seconds(f64),
+    Beats(f64),
+    Bars(f64),
+}
+
+impl Time {
+    fn parse(text: &str) -> Option<Self> {
+        let split = text.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(text.len());
+        let (value, unit) = text.split_at(split);
+        let value: f64 = value.parse().ok()?;
+        match unit {
+            "" | "s" => Some(Self::Seconds(value)),
+            "ms" => Some(Self::Seconds(value / 1000.0)),
+            "b" | "beat" | "beats" => Some(Self::Beats(value)),
+            "bar" | "bars" => Some(Self::Bars(value)),
+            _ => None,
+        }
+    }
+
+    pub fn seconds(&self, bpm: f32) -> f64 {
+        match self {
+            Self::Seconds(s) => *s,
+            Self::Beats(b) => b * 60.0 / bpm as f64,
+            Self::Bars(b) => b * sequence::bar_length(bpm),
+        }
+    }
+}
+
+/// Parses a note name with octave: a letter, an optional `#` or `b`, then the octave, e.g. `C#4`.
+pub fn parse_note(text: &str) -> Option<(Note, i32)> {
+    let mut chars = text.chars();
+    let base = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0, 'D' => 2, 'E' => 4, 'F' => 5, 'G' => 7, 'A' => 9, 'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (shift, octave) = match rest.chars().next()? {
+        '#' => (1, &rest[1..]),
+        'b' => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+    let semitone = 12 * octave.parse::<i32>().ok()? + base + shift;
+    Some((Note::from_index(semitone.rem_euclid(12) as usize), semitone.div_euclid(12)))
+}
+
+
+/// A word of a command line, unquoted and unescaped, with the columns it spans.
+#[derive(Debug, PartialEq)]
+pub struct Token {
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A command line that couldn't be parsed, and the columns of the part at fault.
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ParseError {
+    fn new(message: String, start: usize, end: usize) -> Self {
+        Self { message, start, end }
+    }
+
+    fn at(token: &Token, message: String) -> Self {
+        Self::new(message, token.start, token.end)
+    }
+
+    /// Carets under the part of the line at fault, to print beneath it.
+    pub fn pointer(&self) -> String {
+        format!("{}{}", " ".repeat(self.start), "^".repeat((self.end - self.start).max(1)))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Splits a command line into words on whitespace. Double quotes group words and allow escapes
+/// (`\"`, `\\`); single quotes group words literally; outside quotes a backslash escapes the next
+/// character.
+pub fn tokenize(line: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().enumerate().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let mut text = String::new();
+        let mut end = start;
+        while let Some(&(i, c)) = chars.peek() {
+            if c.is_whitespace() {
+                break;
+            }
+            chars.next();
+            end = i + 1;
+            match c {
+                '"' | '\'' => {
+                    let quote = c;
+                    loop {
+                        let Some((i, c)) = chars.next()
+                        else {
+                            return Err(ParseError::new("unterminated quote".into(), start, line.chars().count()));
+                        };
+                        end = i + 1;
+                        match c {
+                            c if c == quote => break,
+                            '\\' if quote == '"' => match chars.next() {
+                                Some((_, c)) => text.push(c),
+                                None => return Err(ParseError::new("unterminated quote".into(), start, end)),
+                            },
+                            c => text.push(c),
+                        }
+                    }
+                },
+                '\\' => match chars.next() {
+                    Some((i, c)) => {
+                        text.push(c);
+                        end = i + 1;
+                    },
+                    None => return Err(ParseError::new("nothing to escape".into(), i, end)),
+                },
+                c => text.push(c),
+            }
+        }
+        tokens.push(Token { text, start, end });
+    }
+    Ok(tokens)
+}
+
+
+#[derive(Debug)]
+enum ArgValue {
+    Text(String),
+    Number(f64),
+    Time(Time),
+    Note(Note, i32),
+}
+
+/// Parsed arguments of a command, taken in order by its builder.
+pub struct Args(std::vec::IntoIter<ArgValue>);
+
+impl Args {
+    pub fn text(&mut self) -> String {
+        match self.0.next() {
+            Some(ArgValue::Text(s)) => s,
+            v => panic!("expected text argument, got {v:?}"),
+        }
+    }
+
+    pub fn number(&mut self) -> f64 {
+        match self.0.next() {
+            Some(ArgValue::Number(n)) => n,
+            v => panic!("expected number argument, got {v:?}"),
+        }
+    }
+
+    pub fn time(&mut self) -> Time {
+        match self.0.next() {
+            Some(ArgValue::Time(t)) => t,
+            v => panic!("expected time argument, got {v:?}"),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn note(&mut self) -> (Note, i32) {
+        match self.0.next() {
+            Some(ArgValue::Note(n, o)) => (n, o),
+            v => panic!("expected note argument, got {v:?}"),
+        }
+    }
+}
+
+/// A command: the words that name it, the arguments that follow, what it does, and how to build it
+/// from them.
+pub struct CommandSpec<C> {
+    pub words: &'static str,
+    pub args: Vec<Arg>,
+    pub about: &'static str,
+    pub build: fn(Args) -> C,
+}
+
+impl<C> CommandSpec<C> {
+    pub fn new(words: &'static str, args: Vec<Arg>, about: &'static str, build: fn(Args) -> C) -> Self {
+        Self { words, args, about, build }
+    }
+
+    /// The command with placeholders for its arguments.
+    pub fn usage(&self) -> String {
+        let mut rv = self.words.to_string();
+        for arg in self.args.iter() {
+            rv += " ";
+            rv += &arg.placeholder();
+        }
+        rv
+    }
+}
+
+/// Lines of help for the commands in `table`: the first word of each if `topic` is empty, or how to
+/// use those whose words start with `topic`.
+pub fn help<C>(table: &[CommandSpec<C>], topic: &str) -> Result<Vec<String>, String> {
+    const WIDTH: usize = 60;
+
+    if topic.trim().is_empty() {
+        let mut firsts: Vec<&str> = Vec::new();
+        for spec in table.iter() {
+            let first = spec.words.split_whitespace().next().unwrap_or_default();
+            if !firsts.contains(&first) {
+                firsts.push(first);
+            }
+        }
+        let mut rv = vec![String::from("Commands:")];
+        for first in firsts {
+            match rv.last_mut() {
+                Some(line) if line.len() + first.len() < WIDTH => *line += &format!(" {first}"),
+                _ => rv.push(format!("{:10}{first}", "")),
+            }
+        }
+        rv.push("Type help and a command, e.g. help load, for how to use it.".into());
+        return Ok(rv);
+    }
+
+    let topic: Vec<_> = topic.split_whitespace().collect();
+    let mut rv = Vec::new();
+    for spec in table.iter() {
+        let words: Vec<_> = spec.words.split_whitespace().collect();
+        if !words.starts_with(&topic) {
+            continue;
+        }
+        rv.push(spec.usage());
+        rv.push(format!("    {}", spec.about));
+        let mut described = Vec::new();
+        for arg in spec.args.iter() {
+            let placeholder = arg.placeholder();
+            if !described.contains(&placeholder) {
+                rv.push(format!("    {placeholder}: {}", arg.describe()));
+                described.push(placeholder);
+            }
+        }
+    }
+    if rv.is_empty() {
+        return Err(format!("no command \"{}\"", topic.join(" ")));
+    }
+    Ok(rv)
+}
+
+/// Parses `line` as one of the commands in `table`, preferring the one named by the most words.
+pub fn parse<C>(table: &[CommandSpec<C>], line: &str) -> Result<C, ParseError> {
+    let tokens = tokenize(line)?;
+    let Some(first) = tokens.first()
+    else {
+        return Err(ParseError::new("no command given".into(), 0, 0));
+    };
+
+    let matched = |spec: &CommandSpec<C>| {
+        spec.words.split_whitespace().zip(tokens.iter()).take_while(|(w, t)| *w == t.text).count()
+    };
+    let spec = table.iter()
+        .filter(|spec| matched(spec) == spec.words.split_whitespace().count())
+        .max_by_key(|spec| matched(spec));
+
+    let Some(spec) = spec
+    else {
+        // point at the first word no command continues with
+        let depth = table.iter().map(&matched).max().unwrap_or(0);
+        let known: Vec<_> = table.iter()
+            .filter(|s| matched(s) == depth)
+            .filter_map(|s| s.words.split_whitespace().nth(depth))
+            .collect();
+        let message = match tokens.get(depth) {
+            Some(t) if depth == 0 => format!("unknown command \"{}\"", t.text),
+            Some(t) => format!("unknown command \"{}\"; expected {}", t.text, known.join(", ")),
+            None => format!("incomplete command; expected {}", known.join(", ")),
+        };
+        return Err(match tokens.get(depth) {
+            Some(t) => ParseError::at(t, message),
+            None => ParseError::new(message, line.chars().count(), line.chars().count() + 1),
+        });
+    };
+
+    let n_words = spec.words.split_whitespace().count();
+    let rest = &tokens[n_words..];
+    // taken as typed, spacing and all, unless it is a single (perhaps quoted) token
+    let rest_of_line = || {
+        let text = match rest {
+            [] => String::new(),
+            [token] => token.text.clone(),
+            [first, ..] => line.chars().skip(first.start).collect::<String>().trim_end().to_string(),
+        };
+        Ok((spec.build)(Args(vec![ArgValue::Text(text)].into_iter())))
+    };
+    match spec.args[..] {
+        [Arg::Command] => return rest_of_line(),
+        [Arg::MidiPort] if !rest.is_empty() => return rest_of_line(),
+        _ => (),
+    }
+    if let Some(extra) = rest.get(spec.args.len()) {
+        let last = tokens.last().unwrap_or(first);
+        let message = format!("unexpected argument \"{}\" to \"{}\"", extra.text, spec.words);
+        return Err(ParseError::new(message, extra.start, last.end));
+    }
+    if let Some(missing) = spec.args.get(rest.len()) {
+        let end = line.chars().count();
+        let message = format!("\"{}\" needs {}", spec.words, missing.placeholder());
+        return Err(ParseError::new(message, end, end + 1));
+    }
+
+    let values = spec.args.iter()
+        .zip(rest)
+        .map(|(arg, token)| arg.parse(&token.text).map_err(|m| ParseError::at(token, m)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((spec.build)(Args(values.into_iter())))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(line: &str) -> Vec<String> {
+        tokenize(line).unwrap().into_iter().map(|t| t.text).collect()
+    }
+
+    #[test]
+    fn tokenizes_quotes_and_escapes() {
+        assert_eq!(texts("  load   track x.yaml "), ["load", "track", "x.yaml"]);
+        assert_eq!(texts(r#"load track "my songs/a b.yaml""#), ["load", "track", "my songs/a b.yaml"]);
+        assert_eq!(texts(r#"say "a \"quoted\" \\ word""#), ["say", r#"a "quoted" \ word"#]);
+        assert_eq!(texts(r"a\ b 'c \ d'"), ["a b", r"c \ d"]);
+        assert_eq!(texts(r#"pre"fix"ed"#), ["prefixed"]);
+
+        let tokens = tokenize(r#"x "y z""#).unwrap();
+        assert_eq!((tokens[1].start, tokens[1].end), (2, 7));
+        assert_eq!(tokenize(r#"load "oops"#).unwrap_err(), ParseError::new("unterminated quote".into(), 5, 10));
+    }
+
+    #[test]
+    fn parses_typed_args() {
+        assert_eq!(Time::parse("1.5"), Some(Time::Seconds(1.5)));
+        assert_eq!(Time::parse("250ms"), Some(Time::Seconds(0.25)));
+        assert_eq!(Time::parse("2b"), Some(Time::Beats(2.0)));
+        assert_eq!(Time::parse("1bar"), Some(Time::Bars(1.0)));
+        assert_eq!(Time::parse("1x"), None);
+        assert_eq!(Time::Beats(2.0).seconds(120.0), 1.0);
+
+        assert_eq!(parse_note("C4"), Some((Note::C, 4)));
+        assert_eq!(parse_note("f#3"), Some((Note::FSharp, 3)));
+        assert_eq!(parse_note("Bb2"), Some((Note::ASharp, 2)));
+        assert_eq!(parse_note("Cb4"), Some((Note::B, 3)));
+        assert_eq!(parse_note("A-1"), Some((Note::A, -1)));
+        assert_eq!(parse_note("H2"), None);
+        assert_eq!(parse_note("C"), None);
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Cmd {
+        Play,
+        PlaySequence(String),
+        Seek(Time),
+        Fade(Time, f64),
+        Connect(String),
+        Tune((Note, i32)),
+        Help(String),
+    }
+
+    fn table() -> Vec<CommandSpec<Cmd>> {
+        vec![
+            CommandSpec::new("play", vec![], "Play.", |_| Cmd::Play),
+            CommandSpec::new("play sequence", vec![Arg::SequenceName], "Play a sequence.", |mut a| Cmd::PlaySequence(a.text())),
+            CommandSpec::new("seek", vec![Arg::Time], "Seek.", |mut a| Cmd::Seek(a.time())),
+            CommandSpec::new("fade", vec![Arg::Time, Arg::Number], "Fade.", |mut a| Cmd::Fade(a.time(), a.number())),
+            CommandSpec::new("connect", vec![Arg::MidiPort], "Connect.", |mut a| Cmd::Connect(a.text())),
+            CommandSpec::new("tune", vec![Arg::Note], "Tune.", |mut a| Cmd::Tune(a.note())),
+            CommandSpec::new("help", vec![Arg::Command], "Help.", |mut a| Cmd::Help(a.text())),
+        ]
+    }
+
+    #[test]
+    fn parses_against_table() {
+        let table = table();
+        assert_eq!(parse(&table, "play"), Ok(Cmd::Play));
+        assert_eq!(parse(&table, "play sequence 'the verse'"), Ok(Cmd::PlaySequence("the verse".into())));
+        assert_eq!(parse(&table, " seek  4b"), Ok(Cmd::Seek(Time::Beats(4.0))));
+        assert_eq!(parse(&table, "fade 2b 0.5"), Ok(Cmd::Fade(Time::Beats(2.0), 0.5)));
+        assert_eq!(parse(&table, "connect Midi Through  Port-0 "), Ok(Cmd::Connect("Midi Through  Port-0".into())));
+        assert_eq!(parse(&table, "connect 'Midi Through'"), Ok(Cmd::Connect("Midi Through".into())));
+        assert_eq!(parse(&table, "tune Bb2"), Ok(Cmd::Tune((Note::ASharp, 2))));
+        assert_eq!(parse(&table, "help"), Ok(Cmd::Help("".into())));
+        assert_eq!(parse(&table, "help  play sequence"), Ok(Cmd::Help("play sequence".into())));
+
+        let err = |line| parse(&table, line).unwrap_err();
+        assert_eq!(err("stpo").message, "unknown command \"stpo\"");
+        assert_eq!((err("play foo").start, err("play foo").end), (5, 8));
+        assert_eq!(err("fade 2b loud").pointer(), "        ^^^^");
+        assert_eq!(err("fade 2b loud").message, "\"loud\" is not a number");
+        assert_eq!(err("tune  H2").pointer(), "      ^^");
+        assert_eq!(err("tune  H2").message, "\"H2\" is not a note (e.g. C4, F#3 or Bb2)");
+        assert_eq!(err("seek").message, "\"seek\" needs $time");
+        assert_eq!(err("connect").message, "\"connect\" needs $port");
+        assert_eq!(err("").message, "no command given");
+    }
+
+    #[test]
+    fn generates_help() {
+        let table = table();
+        let all = help(&table, "").unwrap();
+        assert_eq!(all[0], "Commands: play seek fade connect tune help");
+
+        let play = help(&table, "play").unwrap();
+        assert_eq!(play.len(), 5);
+        assert_eq!(play[2..], ["play sequence $name", "    Play a sequence.", "    $name: name of a sequence in the track"]);
+        assert_eq!(help(&table, "play seq"), Err("no command \"play seq\"".into()));
+    }
+}
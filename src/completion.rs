@@ -0,0 +1,147 @@
+use std::fs;
+use std::path::Path;
+
+
+/// Replaces a leading `~` with the user's home directory.
+pub fn expand_home(path: &str) -> String {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => return path.to_string(),
+    };
+    match std::env::var("HOME") {
+        Ok(home) => format!("{home}{rest}"),
+        Err(_) => path.to_string(),
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of characters and `?` for any one.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    fn matches(p: &[char], n: &[char]) -> bool {
+        match (p.first(), n.first()) {
+            (None, None) => true,
+            (Some('*'), _) => matches(&p[1..], n) || (!n.is_empty() && matches(p, &n[1..])),
+            (Some('?'), Some(_)) => matches(&p[1..], &n[1..]),
+            (Some(a), Some(b)) if a == b => matches(&p[1..], &n[1..]),
+            _ => false,
+        }
+    }
+    let p: Vec<_> = pattern.chars().collect();
+    let n: Vec<_> = name.chars().collect();
+    matches(&p, &n)
+}
+
+/// Paths that `partial` could be completed to: directories (ending in `/`, to complete into) and
+/// files matching `glob`, in name order. Hidden entries are left out unless `partial` names one.
+/// Paths are given as typed, so a leading `~` stays unexpanded.
+pub fn complete_path(partial: &str, glob: &str) -> Vec<String> {
+    if partial == "~" {
+        return vec!["~/".to_string()];
+    }
+    let split = partial.rfind('/').map_or(0, |i| i + 1);
+    let (dir, prefix) = partial.split_at(split);
+    let read_from = if dir.is_empty() { ".".to_string() } else { expand_home(dir) };
+    let Ok(entries) = fs::read_dir(&read_from)
+    else {
+        return Vec::new();
+    };
+
+    let mut rv = Vec::new();
+    for entry in entries.flatten() {
+        let Ok(name) = entry.file_name().into_string()
+        else {
+            continue;
+        };
+        if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+            continue;
+        }
+        // follow links, so a link to a directory can be completed into
+        if Path::new(&read_from).join(&name).is_dir() {
+            rv.push(format!("{dir}{name}/"));
+        }
+        else if glob_match(glob, &name) {
+            rv.push(format!("{dir}{name}"));
+        }
+    }
+    rv.sort();
+    rv
+}
+
+/// The last word of `line` as the tokenizer would read it so far, and the quote left open, if any.
+pub fn partial_word(line: &str) -> (String, Option<char>) {
+    let mut word = String::new();
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => word.clear(),
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '\\') | (Some('"'), '\\') => word.extend(chars.next()),
+            (_, c) => word.push(c),
+        }
+    }
+    (word, quote)
+}
+
+/// Escapes `text` for typing after a partial word left inside `quote`.
+pub fn escape(text: &str, quote: Option<char>) -> String {
+    let mut rv = String::new();
+    for c in text.chars() {
+        let special = match quote {
+            None => c.is_whitespace() || matches!(c, '"' | '\'' | '\\'),
+            Some('"') => matches!(c, '"' | '\\'),
+            Some(_) => false,
+        };
+        if special {
+            rv.push('\\');
+        }
+        rv.push(c);
+    }
+    rv
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("*.yaml", "track.yaml"));
+        assert!(glob_match("*.yaml", ".yaml"));
+        assert!(!glob_match("*.yaml", "track.yml"));
+        assert!(glob_match("t?ack*", "track.yaml"));
+        assert!(glob_match("*", "anything"));
+    }
+
+    #[test]
+    fn completes_paths() {
+        let scratch = TestDir::new("completion");
+        let root = scratch.path();
+        fs::create_dir_all(root.join("songs/old")).unwrap();
+        for f in ["a.yaml", "my song.yaml", "b.wav", ".hidden.yaml", "songs/c.yaml"] {
+            fs::write(root.join(f), "").unwrap();
+        }
+        let dir = format!("{}/", root.display());
+
+        let names = |partial: &str| -> Vec<String> {
+            complete_path(partial, "*.yaml").into_iter().map(|p| p[dir.len()..].to_string()).collect()
+        };
+        assert_eq!(names(&dir), ["a.yaml", "my song.yaml", "songs/"]);
+        assert_eq!(names(&format!("{dir}s")), ["songs/"]);
+        assert_eq!(names(&format!("{dir}songs/")), ["songs/c.yaml", "songs/old/"]);
+        assert_eq!(names(&format!("{dir}.")), [".hidden.yaml"]);
+        assert!(complete_path(&format!("{dir}nowhere/"), "*").is_empty());
+        assert_eq!(complete_path("~", "*"), ["~/"]);
+    }
+
+    #[test]
+    fn reads_partial_words() {
+        assert_eq!(partial_word("load track my\\ so"), ("my so".to_string(), None));
+        assert_eq!(partial_word("load track \"my so"), ("my so".to_string(), Some('"')));
+        assert_eq!(partial_word("load track "), (String::new(), None));
+        assert_eq!(escape("ng's.yaml", None), "ng\\'s.yaml");
+        assert_eq!(escape("ng \"x\".yaml", Some('"')), "ng \\\"x\\\".yaml");
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use fundsp::hacker::{AudioUnit, EventId, Fade, Sequencer};
+use ratatui::widgets::{Block, Borders, Paragraph, Widget};
+use ratatui::prelude::*;
+
+use crate::event_handler::EventHandler;
+use crate::frame_renderable::FrameRenderable;
+
+
+/// What a pad plays.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PadSound {
+    /// A patch of the track, e.g. one built around a `Sample` node.
+    Patch(String),
+    /// A WAV file, played as it is.
+    Sample(String),
+}
+
+impl fmt::Display for PadSound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Patch(name) => write!(f, "{name}"),
+            Self::Sample(path) => {
+                let file = std::path::Path::new(path).file_name().map_or(path.clone(), |f| f.to_string_lossy().into_owned());
+                write!(f, "{file}")
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pad {
+    sound: Option<PadSound>,
+    /// Pads in the same group cut each other off, like open and closed hi-hats.
+    choke: Option<usize>,
+    hit: Option<Instant>,
+}
+
+/// A pad's sound on the sequencer.
+struct Sounding {
+    pad: usize,
+    event_id: EventId,
+    ends: Instant,
+}
+
+
+/// Sixteen pads played from a 4x4 block of the keyboard, each firing a one-shot sound.
+pub struct DrumPads {
+    pads: Vec<Pad>,
+    /// Pads hit since last asked.
+    hits: Vec<usize>,
+    sounding: Vec<Sounding>,
+    finished: bool,
+}
+
+impl DrumPads {
+    /// Key for each pad, row by row from the top left.
+    pub const KEYS: [char; 16] = ['1', '2', '3', '4', 'q', 'w', 'e', 'r', 'a', 's', 'd', 'f', 'z', 'x', 'c', 'v'];
+    /// How long a pad stays lit after a hit.
+    const FLASH: Duration = Duration::from_millis(150);
+    /// Fade for sounds cut off by their choke group.
+    const CHOKE_FADE: f64 = 0.005;
+    const PAD_HEIGHT: u16 = 5;
+
+    pub fn new() -> Self {
+        Self {
+            pads: (0..Self::KEYS.len()).map(|_| Pad::default()).collect(),
+            hits: Vec::new(),
+            sounding: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Index of the pad numbered `number`, counting from 1 at the top left.
+    pub fn index(number: f64) -> anyhow::Result<usize> {
+        if number.fract() != 0.0 || !(1.0..=Self::KEYS.len() as f64).contains(&number) {
+            bail!("pads are numbered 1 to {}, not {number}", Self::KEYS.len());
+        }
+        Ok(number as usize - 1)
+    }
+
+    pub fn bind(&mut self, pad: usize, sound: PadSound) {
+        self.pads[pad].sound = Some(sound);
+    }
+
+    /// Puts `pad` in choke group `group`, or in none if `None`.
+    pub fn set_choke(&mut self, pad: usize, group: Option<usize>) {
+        self.pads[pad].choke = group;
+    }
+
+    pub fn sound(&self, pad: usize) -> Option<&PadSound> {
+        self.pads[pad].sound.as_ref()
+    }
+
+    /// Pads hit since last asked, in order.
+    pub fn take_hits(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.hits)
+    }
+
+    /// Plays `unit` for `length` seconds as `pad`'s sound, first cutting off whatever its choke
+    /// group is playing.
+    pub fn trigger(&mut self, seq: &mut Sequencer, pad: usize, unit: Box<dyn AudioUnit>, length: f64) {
+        if let Some(group) = self.pads[pad].choke {
+            let pads = &self.pads;
+            self.sounding.retain(|s| {
+                let choked = pads[s.pad].choke == Some(group);
+                if choked {
+                    seq.edit_relative(s.event_id, Self::CHOKE_FADE, Self::CHOKE_FADE);
+                }
+                !choked
+            });
+        }
+        let event_id = seq.push_relative(0.0, length, Fade::Power, 0.0, 0.0, unit);
+        let ends = Instant::now() + Duration::from_secs_f64(length);
+        self.sounding.push(Sounding { pad, event_id, ends });
+    }
+
+    /// Forgets sounds that have finished; call regularly.
+    pub fn update(&mut self) {
+        let now = Instant::now();
+        self.sounding.retain(|s| s.ends > now);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn set_unfinished(&mut self) {
+        self.finished = false;
+    }
+
+    fn draw_pad(&self, frame: &mut Frame, area: Rect, i: usize) {
+        let pad = &self.pads[i];
+        let lit = pad.hit.is_some_and(|t| t.elapsed() < Self::FLASH);
+        let sounding = self.sounding.iter().any(|s| s.pad == i);
+
+        let mut lines = vec![match &pad.sound {
+            Some(sound) => Line::from(sound.to_string()),
+            None => Line::from("-").dark_gray(),
+        }];
+        if let Some(group) = pad.choke {
+            lines.push(Line::from(format!("choke {group}")).dark_gray());
+        }
+
+        let border = if sounding { Style::new().green() } else { Style::new() };
+        let block = Block::new()
+            .borders(Borders::ALL)
+            .border_style(border)
+            .title(Line::from(format!(" {} ", Self::KEYS[i])).bold())
+            .title(Line::from(format!(" {} ", i + 1)).right_aligned().dark_gray());
+        let style = if lit { Style::new().on_yellow().black().bold() } else { Style::new() };
+        Paragraph::new(lines)
+            .block(block)
+            .style(style)
+            .render(area, frame.buffer_mut());
+    }
+}
+
+
+impl EventHandler for DrumPads {
+    fn handle_key(&mut self, kev: KeyEvent) -> anyhow::Result<bool> {
+        match kev {
+            KeyEvent { code: KeyCode::Esc, kind: KeyEventKind::Press, .. } => {
+                self.finished = true;
+            },
+            // one-shots: only the press counts, not repeats or the release
+            KeyEvent { code: KeyCode::Char(c), kind: KeyEventKind::Press, modifiers: KeyModifiers::NONE, .. } => {
+                if let Some(i) = Self::KEYS.iter().position(|k| *k == c) {
+                    self.pads[i].hit = Some(Instant::now());
+                    self.hits.push(i);
+                }
+            },
+            _ => (),
+        }
+        Ok(false)
+    }
+}
+
+
+impl FrameRenderable for DrumPads {
+    fn draw_into(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::new()
+            .borders(Borders::ALL)
+            .title(" pads ")
+            .title_bottom(Line::from("1-4, q-r, a-f and z-v hit the pads; esc back.").centered());
+        let inner = block.inner(area);
+        block.render(area, frame.buffer_mut());
+
+        let rows = Layout::new(Direction::Vertical, [Constraint::Length(Self::PAD_HEIGHT); 4]).split(inner);
+        for (r, row) in rows.iter().enumerate() {
+            let cells = Layout::new(Direction::Horizontal, [Constraint::Ratio(1, 4); 4]).split(*row);
+            for (c, cell) in cells.iter().enumerate() {
+                self.draw_pad(frame, *cell, r * 4 + c);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fundsp::hacker::dc;
+
+    #[test]
+    fn hits_and_chokes() {
+        let mut pads = DrumPads::new();
+        assert_eq!(DrumPads::index(1.0).unwrap(), 0);
+        assert_eq!(DrumPads::index(16.0).unwrap(), 15);
+        assert!(DrumPads::index(17.0).is_err());
+        assert!(DrumPads::index(2.5).is_err());
+
+        let press = |c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+        let mut release = press('q');
+        release.kind = KeyEventKind::Release;
+        for kev in [press('1'), press('q'), release, press('v'), press('y')] {
+            pads.handle_key(kev).unwrap();
+        }
+        assert_eq!(pads.take_hits(), [0, 4, 15]);
+        assert!(pads.take_hits().is_empty());
+
+        // the closed hat cuts the open one off; the kick, in no group, rings on
+        let mut seq = Sequencer::new(false, 1);
+        for (pad, group) in [(0, None), (1, Some(1)), (2, Some(1))] {
+            pads.set_choke(pad, group);
+        }
+        for pad in [0, 1, 2] {
+            pads.trigger(&mut seq, pad, Box::new(dc(1.0)), 10.0);
+        }
+        let sounding: Vec<_> = pads.sounding.iter().map(|s| s.pad).collect();
+        assert_eq!(sounding, [0, 2]);
+    }
+}
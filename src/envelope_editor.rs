@@ -0,0 +1,329 @@
+use std::cell::Cell;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
+use ratatui::symbols::Marker;
+use ratatui::widgets::canvas::{Canvas, Line as CanvasLine, Points};
+use ratatui::widgets::{Block, Borders, Paragraph, Widget};
+use ratatui::prelude::*;
+
+use crate::event_handler::EventHandler;
+use crate::frame_renderable::FrameRenderable;
+use crate::patch::PatchNode;
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+impl Stage {
+    const ALL: [Stage; 4] = [Stage::Attack, Stage::Decay, Stage::Sustain, Stage::Release];
+}
+
+
+/// Editor for an ADSR envelope, drawn as its shape with a handle at the end of each stage to drag.
+pub struct EnvelopeEditor {
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+    stage: Stage,
+    /// Seconds across the canvas, held while dragging so the shape doesn't rescale under the mouse.
+    span: f32,
+    dragging: Option<Stage>,
+    /// Where the canvas was last drawn, to find what the mouse is over.
+    canvas: Cell<Rect>,
+    /// Whether keys go to the editor; the patch editor showing it hands them on.
+    focused: bool,
+    changed: bool,
+}
+
+impl EnvelopeEditor {
+    /// Shortest time a stage can be set to from the keys.
+    const MIN_TIME: f32 = 0.001;
+    /// Factor the keys scale a time by.
+    const STEP: f32 = 1.25;
+    const SUSTAIN_STEP: f32 = 0.05;
+
+    /// An editor for `node`, if it is an envelope.
+    pub fn new(node: &PatchNode) -> Option<Self> {
+        let mut rv = Self {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 0.0,
+            release: 0.0,
+            stage: Stage::Attack,
+            span: 1.0,
+            dragging: None,
+            canvas: Cell::new(Rect::default()),
+            focused: false,
+            changed: false,
+        };
+        rv.set_node(node).then_some(rv)
+    }
+
+    /// Takes the envelope's values from `node`, returning false if it isn't an envelope.
+    pub fn set_node(&mut self, node: &PatchNode) -> bool {
+        let PatchNode::ADSR { attack, decay, sustain, release } = *node
+        else {
+            return false;
+        };
+        (self.attack, self.decay, self.sustain, self.release) = (attack, decay, sustain, release);
+        if self.dragging.is_none() {
+            self.fit_span();
+        }
+        true
+    }
+
+    pub fn node(&self) -> PatchNode {
+        PatchNode::ADSR { attack: self.attack, decay: self.decay, sustain: self.sustain, release: self.release }
+    }
+
+    /// The envelope as edited, if it has changed since last asked.
+    pub fn take_changed(&mut self) -> Option<PatchNode> {
+        if self.changed {
+            self.changed = false;
+            Some(self.node())
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn is_focused(&self) -> bool {
+        self.focused
+    }
+
+    pub fn focus(&mut self) {
+        self.focused = true;
+    }
+
+    /// Time drawn for the sustain stage, which has no length of its own.
+    fn hold(&self) -> f32 {
+        self.span / 5.0
+    }
+
+    /// Leaves room past the end of the release to drag it further.
+    fn fit_span(&mut self) {
+        self.span = ((self.attack + self.decay + self.release) * 1.5).max(1.0);
+    }
+
+    /// Where each stage ends, in seconds and level.
+    fn handles(&self) -> [(Stage, f64, f64); 4] {
+        let a = self.attack;
+        let d = a + self.decay;
+        let s = d + self.hold();
+        let r = s + self.release;
+        [
+            (Stage::Attack, a as f64, 1.0),
+            (Stage::Decay, d as f64, self.sustain as f64),
+            (Stage::Sustain, s as f64, self.sustain as f64),
+            (Stage::Release, r as f64, 0.0),
+        ]
+    }
+
+    /// Steps the selected stage's value up or down.
+    fn nudge(&mut self, up: bool) {
+        let scale = |t: f32| if up { (t * Self::STEP).max(Self::MIN_TIME) } else { t / Self::STEP };
+        match self.stage {
+            Stage::Attack => self.attack = scale(self.attack),
+            Stage::Decay => self.decay = scale(self.decay),
+            Stage::Release => self.release = scale(self.release),
+            Stage::Sustain => {
+                let step = if up { Self::SUSTAIN_STEP } else { -Self::SUSTAIN_STEP };
+                self.sustain = (self.sustain + step).clamp(0.0, 1.0);
+            },
+        }
+        self.fit_span();
+        self.changed = true;
+    }
+
+    /// The canvas point under the cell at `column`, `row`, if it's on the canvas.
+    fn point_at(&self, column: u16, row: u16) -> Option<(f32, f32)> {
+        let area = self.canvas.get();
+        if area.width == 0 || area.height == 0 {
+            return None;
+        }
+        let x = (column as f32 - area.x as f32 + 0.5) / area.width as f32 * self.span;
+        let y = 1.0 - (row as f32 - area.y as f32 + 0.5) / area.height as f32;
+        Some((x, y))
+    }
+
+    /// Sets the dragged stage from where the mouse is, keeping each handle after the one before.
+    fn drag_to(&mut self, stage: Stage, x: f32, y: f32) {
+        let [(_, a, _), _, (_, s, _), _] = self.handles();
+        let level = y.clamp(0.0, 1.0);
+        match stage {
+            Stage::Attack => self.attack = x.max(0.0),
+            Stage::Decay => {
+                self.decay = (x - a as f32).max(0.0);
+                self.sustain = level;
+            },
+            Stage::Sustain => self.sustain = level,
+            Stage::Release => self.release = (x - s as f32).max(0.0),
+        }
+        self.stage = stage;
+        self.changed = true;
+    }
+
+    fn value_text(&self, stage: Stage) -> String {
+        match stage {
+            Stage::Attack => format!("A {:.3}s", self.attack),
+            Stage::Decay => format!("D {:.3}s", self.decay),
+            Stage::Sustain => format!("S {:.2}", self.sustain),
+            Stage::Release => format!("R {:.3}s", self.release),
+        }
+    }
+}
+
+
+impl EventHandler for EnvelopeEditor {
+    fn handle_key(&mut self, kev: KeyEvent) -> anyhow::Result<bool> {
+        if kev.kind != KeyEventKind::Press {
+            return Ok(false);
+        }
+        let i = Stage::ALL.iter().position(|s| *s == self.stage).unwrap_or(0);
+        match kev.code {
+            KeyCode::Esc => self.focused = false,
+            KeyCode::Left | KeyCode::BackTab => self.stage = Stage::ALL[(i + 3) % 4],
+            KeyCode::Right | KeyCode::Tab => self.stage = Stage::ALL[(i + 1) % 4],
+            KeyCode::Up => self.nudge(true),
+            KeyCode::Down => self.nudge(false),
+            KeyCode::Char(c) if kev.modifiers == KeyModifiers::NONE => {
+                if let Some(stage) = Stage::ALL.iter().find(|s| format!("{s:?}").starts_with(c.to_ascii_uppercase())) {
+                    self.stage = *stage;
+                }
+            },
+            _ => (),
+        }
+        Ok(false)
+    }
+
+    fn handle_mouse(&mut self, mev: MouseEvent) -> anyhow::Result<bool> {
+        match mev.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                let area = self.canvas.get();
+                let Some((x, y)) = self.point_at(mev.column, mev.row)
+                else {
+                    return Ok(false);
+                };
+                if !area.contains(Position::new(mev.column, mev.row)) {
+                    return Ok(false);
+                }
+                // the nearest handle, measured in cells so either axis counts the same
+                let (w, h) = (self.span as f64 / area.width as f64, 1.0 / area.height as f64);
+                let nearest = self.handles()
+                    .into_iter()
+                    .min_by(|(_, ax, ay), (_, bx, by)| {
+                        let dist = |hx: f64, hy: f64| ((hx - x as f64) / w).powi(2) + ((hy - y as f64) / h).powi(2);
+                        dist(*ax, *ay).total_cmp(&dist(*bx, *by))
+                    })
+                    .map(|(stage, _, _)| stage);
+                self.dragging = nearest;
+                if let Some(stage) = nearest {
+                    self.stage = stage;
+                    self.focused = true;
+                }
+            },
+            MouseEventKind::Drag(MouseButton::Left) => {
+                if let (Some(stage), Some((x, y))) = (self.dragging, self.point_at(mev.column, mev.row)) {
+                    self.drag_to(stage, x, y);
+                }
+            },
+            MouseEventKind::Up(MouseButton::Left) if self.dragging.take().is_some() => self.fit_span(),
+            _ => (),
+        }
+        Ok(false)
+    }
+}
+
+
+impl FrameRenderable for EnvelopeEditor {
+    fn draw_into(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::new()
+            .borders(Borders::ALL)
+            .border_style(if self.focused { Style::new().yellow() } else { Style::new() })
+            .title(" envelope ")
+            .title_bottom(Line::from("left/right stage; up/down or drag to change; esc back.").centered());
+        let inner = block.inner(area);
+        block.render(area, frame.buffer_mut());
+        let [canvas, values] = Layout::new(Direction::Vertical, vec![
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ]).areas(inner);
+        self.canvas.set(canvas);
+
+        let handles = self.handles();
+        let stage = self.stage;
+        Canvas::default()
+            .x_bounds([0.0, self.span as f64])
+            .y_bounds([0.0, 1.0])
+            .marker(Marker::Braille)
+            .paint(move |ctx| {
+                let mut from = (0.0, 0.0);
+                for (s, x, y) in handles {
+                    let color = if s == stage { Color::Yellow } else { Color::Gray };
+                    ctx.draw(&CanvasLine { x1: from.0, y1: from.1, x2: x, y2: y, color });
+                    from = (x, y);
+                }
+                ctx.layer();
+                for (s, x, y) in handles {
+                    let color = if s == stage { Color::Yellow } else { Color::White };
+                    ctx.draw(&Points { coords: &[(x, y)], color });
+                }
+            })
+            .render(canvas, frame.buffer_mut());
+
+        let spans: Vec<Span> = Stage::ALL.iter()
+            .flat_map(|s| {
+                let text = Span::raw(self.value_text(*s));
+                [if *s == self.stage { text.reversed() } else { text }, Span::raw("  ")]
+            })
+            .collect();
+        Paragraph::new(Line::from(spans)).render(values, frame.buffer_mut());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adjusts_stages() {
+        let mut env = EnvelopeEditor::new(&PatchNode::ADSR { attack: 0.1, decay: 0.2, sustain: 0.5, release: 0.4 }).unwrap();
+        assert!(EnvelopeEditor::new(&PatchNode::Saw).is_none());
+        assert!(env.take_changed().is_none());
+
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        env.handle_key(key(KeyCode::Up)).unwrap();
+        env.handle_key(key(KeyCode::Char('s'))).unwrap();
+        env.handle_key(key(KeyCode::Down)).unwrap();
+        let Some(PatchNode::ADSR { attack, sustain, .. }) = env.take_changed()
+        else {
+            panic!("expected an envelope");
+        };
+        assert!((attack - 0.125).abs() < 1e-6);
+        assert!((sustain - 0.45).abs() < 1e-6);
+
+        // drag the end of the release most of the way across a 100x10 canvas
+        env.canvas.set(Rect::new(0, 0, 100, 10));
+        let span = env.span;
+        let [_, _, (_, sustain_end, _), _] = env.handles();
+        let mouse = |kind, column, row| MouseEvent { kind, column, row, modifiers: KeyModifiers::NONE };
+        let (_, x, _) = env.handles()[3];
+        env.handle_mouse(mouse(MouseEventKind::Down(MouseButton::Left), (x / span as f64 * 100.0) as u16, 9)).unwrap();
+        assert_eq!(env.dragging, Some(Stage::Release));
+        env.handle_mouse(mouse(MouseEventKind::Drag(MouseButton::Left), 90, 9)).unwrap();
+        env.handle_mouse(mouse(MouseEventKind::Up(MouseButton::Left), 90, 9)).unwrap();
+        let Some(PatchNode::ADSR { release, .. }) = env.take_changed()
+        else {
+            panic!("expected an envelope");
+        };
+        assert!((release - (0.905 * span - sustain_end as f32)).abs() < 1e-4, "release {release}");
+        assert!(env.node().check().is_ok());
+    }
+}
@@ -0,0 +1,102 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+
+/// Commands entered in the command box, oldest first, kept in a file between sessions.
+pub struct History {
+    entries: Vec<String>,
+    path: Option<PathBuf>,
+}
+
+impl History {
+    /// Most entries kept.
+    const LIMIT: usize = 1000;
+
+    /// A history that isn't saved.
+    pub fn new() -> Self {
+        Self { entries: Vec::new(), path: None }
+    }
+
+    /// Reads the history saved at `path`, if any; new entries are appended to it.
+    pub fn open(path: PathBuf) -> anyhow::Result<Self> {
+        let mut entries: Vec<String> = match fs::read_to_string(&path) {
+            Ok(text) => text.lines().map(String::from).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        if entries.len() > Self::LIMIT {
+            entries.drain(..entries.len() - Self::LIMIT);
+            fs::write(&path, entries.iter().map(|e| format!("{e}\n")).collect::<String>())?;
+        }
+        Ok(Self { entries, path: Some(path) })
+    }
+
+    /// Where the history is kept by default: `$XDG_STATE_HOME/doris/history`, or
+    /// `~/.local/state/doris/history`.
+    pub fn default_path() -> Option<PathBuf> {
+        let state = match std::env::var_os("XDG_STATE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+        };
+        Some(state.join("doris/history"))
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// Records `line` unless it is blank or repeats the last entry.
+    pub fn push(&mut self, line: &str) -> anyhow::Result<()> {
+        if line.trim().is_empty() || line.contains('\n') || self.entries.last().is_some_and(|l| l == line) {
+            return Ok(());
+        }
+        self.entries.push(line.to_string());
+        if self.entries.len() > Self::LIMIT {
+            self.entries.remove(0);
+        }
+
+        if let Some(path) = &self.path {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+
+    /// Index of the latest entry before `before` that contains `query`.
+    pub fn search(&self, query: &str, before: usize) -> Option<usize> {
+        self.entries[..before.min(self.entries.len())]
+            .iter()
+            .rposition(|e| e.contains(query))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn persists_and_searches() {
+        // in a directory of its own that doesn't exist yet, as on a first run
+        let dir = TestDir::new("history");
+        let path = PathBuf::from(dir.file("state/history"));
+
+        let mut history = History::open(path.clone()).unwrap();
+        for line in ["load track a.yaml", "play", "play", "  ", "load track b.yaml", "stop"] {
+            history.push(line).unwrap();
+        }
+        assert_eq!(history.entries(), ["load track a.yaml", "play", "load track b.yaml", "stop"]);
+
+        let history = History::open(path).unwrap();
+        assert_eq!(history.entries().len(), 4);
+        assert_eq!(history.search("load", 4), Some(2));
+        assert_eq!(history.search("load", 2), Some(0));
+        assert_eq!(history.search("load", 0), None);
+        assert_eq!(history.search("render", 4), None);
+    }
+}
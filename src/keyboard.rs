@@ -121,11 +121,12 @@ impl Shape for KB_Keys {
 }
 
 
+#[derive(Debug, PartialEq)]
 pub enum NoteEventKind {
     Start, Stop
 }
 
-#[derive(Clone, Copy, Hash, PartialEq, Eq)]
+#[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
 pub enum Note {
     C, CSharp, D, DSharp, E, F, FSharp, G, GSharp, A, ASharp, B, 
 }
@@ -171,12 +172,33 @@ impl Note {
             _ => { panic!() }
         }
     }
+
+    /// Name as written in notes like `C#4`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::C => "C",
+            Self::CSharp => "C#",
+            Self::D => "D",
+            Self::DSharp => "D#",
+            Self::E => "E",
+            Self::F => "F",
+            Self::FSharp => "F#",
+            Self::G => "G",
+            Self::GSharp => "G#",
+            Self::A => "A",
+            Self::ASharp => "A#",
+            Self::B => "B",
+        }
+    }
 }
 
+#[derive(Debug, PartialEq)]
 pub struct NoteEvent {
     pub kind: NoteEventKind,
     pub note: Note,
     pub octave: i32,
+    /// How hard the note was played, 0...1.
+    pub velocity: f32,
 }
 
 pub struct Keyboard {
@@ -209,7 +231,7 @@ impl Keyboard {
                 let note_index = i % 12;
                 let note = Note::from_index(note_index);
                 let octave = self.octave + (i as i32 / 12);
-                self.events.push(NoteEvent { kind: NoteEventKind::Stop, note, octave });
+                self.events.push(NoteEvent { kind: NoteEventKind::Stop, note, octave, velocity: 0.0 });
             }
         }
     }
@@ -272,7 +294,8 @@ impl EventHandler for Keyboard {
                             self.events.push(NoteEvent {
                                 kind: NoteEventKind::Start,
                                 note,
-                                octave: self.octave + doctave
+                                octave: self.octave + doctave,
+                                velocity: 1.0,
                             });
                         },
                         KeyEventKind::Release => {
@@ -280,7 +303,8 @@ impl EventHandler for Keyboard {
                             self.events.push(NoteEvent {
                                 kind: NoteEventKind::Stop,
                                 note,
-                                octave: self.octave + doctave
+                                octave: self.octave + doctave,
+                                velocity: 0.0,
                             });
                         },
                         _ => ()
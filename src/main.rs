@@ -1,18 +1,36 @@
 mod app;
+mod audio;
+mod cli;
 mod command_box;
+mod command_line;
+mod completion;
+mod drum_pads;
+mod envelope_editor;
 mod event_handler;
 mod frame_renderable;
+mod history;
 mod keyboard;
+mod midi;
 mod patch;
+mod patch_cache;
+mod patch_editor;
+mod render;
+mod script;
 mod sequence;
+mod sequence_editor;
+#[cfg(test)]
+mod test_dir;
 mod track;
+mod transport;
+mod voice;
+mod yaml;
 
 use assert_no_alloc::*;
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{FromSample, SizedSample};
 use fundsp::hacker::*;
 
-use patch::Patch;
+use audio::BackendKind;
+use cli::CliCommand;
+use track::Track;
 
 #[cfg(debug_assertions)] // required when disable_release is set (default)
 #[global_allocator]
@@ -20,91 +38,57 @@ static A: AllocDisabler = AllocDisabler;
 
 
 fn main() {
-    let host = cpal::default_host();
-
-    let device = host
-        .default_output_device()
-        .expect("Failed to find a default output device");
-    let config = device.default_output_config().unwrap();
+    let cmd = match CliCommand::from_args(std::env::args().skip(1)) {
+        Ok(cmd) => cmd,
+        Err(e) => {
+            eprintln!("error: {e}\n\n{}", cli::USAGE);
+            std::process::exit(2);
+        }
+    };
 
-    match config.sample_format() {
-        cpal::SampleFormat::F32 => run::<f32>(&device, &config.into()).unwrap(),
-        cpal::SampleFormat::I16 => run::<i16>(&device, &config.into()).unwrap(),
-        cpal::SampleFormat::U16 => run::<u16>(&device, &config.into()).unwrap(),
-        _ => panic!("Unsupported format"),
+    match cmd {
+        CliCommand::Interactive { audio, script } => {
+            if let Err(e) = run(audio, script) {
+                eprintln!("error: {e}");
+                std::process::exit(1);
+            }
+        }
+        CliCommand::Render { track, output, sample_rate, depth } => {
+            let result = Track::from_file(&track)
+                .and_then(|t| render::render_to_file(&t, &output, sample_rate, depth));
+            if let Err(e) = result {
+                eprintln!("error: failed to render \"{track}\": {e}");
+                std::process::exit(1);
+            }
+        }
     }
 }
 
 
-fn run<T>(device: &cpal::Device, config: &cpal::StreamConfig) -> Result<(), anyhow::Error>
-where
-    T: SizedSample + FromSample<f32>,
-{
-    let sample_rate = config.sample_rate.0 as f64;
-    let channels = config.channels as usize;
-
-    let patch = Patch::new();
-    for r in patch.branch_reprs()? {
-        eprintln!("{r}");
-    }
-    let pn = patch.create_net().unwrap();
-    // let p = unit::<U2, U1>(Box::new(pn));
-
-    //let pitch = shared(150.0);
+fn run(audio: BackendKind, script: Option<String>) -> Result<(), anyhow::Error> {
+    let mut backend = audio.create()?;
+    let sample_rate = backend.sample_rate();
 
     let mut net = Net::new(0, 2);
-    // let (mut s, su) = snoop(32);
-    // net.chain(Box::new(
-    //         (var(&pitch) | constant(1.0)) >> p >> pan(0.0)
-    // ));
-
     net.check();
     net.set_sample_rate(sample_rate);
-    let mut backend = BlockRateAdapter::new(Box::new(net.backend()));
-    let mut next_value = move || assert_no_alloc(|| backend.get_stereo() );
-
-    let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
-
-    let stream = device.build_output_stream(
-        config,
-        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            write_data(data, channels, &mut next_value)
-        },
-        err_fn,
-        None,
-    )?;
-    stream.play()?;
-    // for _ in 0..100 {
-    //     std::thread::sleep(Duration::from_secs(1));
-    //     match s.get() {
-    //         Some(b) => {
-    //             let values: Vec<_> = (0..b.len()).into_iter().map(|i| format!("{:.1}", b.at(i))).collect();
-    //             let line = values.join(", ");
-    //             eprintln!("{line}");
-    //         }
-    //         None => (),
-    //     }
-    // }
-
-    app::App::new(net, sample_rate).run().unwrap();
-    Ok(())
+    let mut block = BlockRateAdapter::new(Box::new(net.backend()));
+    backend.start(Box::new(move || assert_no_alloc(|| block.get_stereo())))?;
+
+    let mut app = app::App::new(net, sample_rate);
+    let result = run_app(&mut app, script);
+    // the audio thread plays the app's nets, and must not see them dropped under it
+    drop(backend);
+    result
 }
 
-fn write_data<T>(output: &mut [T], channels: usize, next_sample: &mut dyn FnMut() -> (f32, f32))
-where
-    T: SizedSample + FromSample<f32>,
-{
-    for frame in output.chunks_mut(channels) {
-        let sample = next_sample();
-        let left = T::from_sample(sample.0);
-        let right: T = T::from_sample(sample.1);
-
-        for (channel, sample) in frame.iter_mut().enumerate() {
-            if channel & 1 == 0 {
-                *sample = left;
-            } else {
-                *sample = right;
-            }
-        }
+fn run_app(app: &mut app::App, script: Option<String>) -> Result<(), anyhow::Error> {
+    if let Some(script) = script {
+        return app.run_batch(&script);
+    }
+    if let Some(rc) = script::rc_path().filter(|p| p.exists())
+        && app.run_script(&rc.to_string_lossy())? {
+        return Ok(());
     }
+    app.run()
 }
@@ -0,0 +1,190 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use anyhow::{anyhow, bail};
+use midir::{MidiInput, MidiInputConnection};
+
+use crate::keyboard::{Note, NoteEvent, NoteEventKind};
+
+
+const CLIENT_NAME: &str = "doris";
+
+/// Messages from a MIDI controller that doris responds to.
+#[derive(Debug, PartialEq)]
+pub enum MidiMessage {
+    Note(NoteEvent),
+    /// Pitch wheel position, -1...1.
+    PitchBend(f32),
+    /// Mod wheel (CC 1) position, 0...1.
+    ModWheel(f32),
+    /// Key pressure, 0...1, of one note (polyphonic aftertouch) or of the whole keyboard.
+    Aftertouch { key: Option<(Note, i32)>, pressure: f32 },
+    /// Sustain pedal (CC 64).
+    Sustain(bool),
+}
+
+impl MidiMessage {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let (&status, data) = bytes.split_first()?;
+        let rv = match (status & 0xF0, data) {
+            (0x90, &[n, v, ..]) if v > 0 => Self::Note(note_event(NoteEventKind::Start, n, v)),
+            (0x80 | 0x90, &[n, _, ..]) => Self::Note(note_event(NoteEventKind::Stop, n, 0)),
+            (0xA0, &[n, v, ..]) => Self::Aftertouch { key: Some(note_key(n)), pressure: v as f32 / 127.0 },
+            (0xD0, &[v, ..]) => Self::Aftertouch { key: None, pressure: v as f32 / 127.0 },
+            (0xB0, &[1, v, ..]) => Self::ModWheel(v as f32 / 127.0),
+            (0xB0, &[64, v, ..]) => Self::Sustain(v >= 64),
+            (0xE0, &[lsb, msb, ..]) => {
+                let value = ((msb as u16) << 7) | lsb as u16;
+                Self::PitchBend((value as f32 - 8192.0) / 8192.0)
+            },
+            _ => return None,
+        };
+        Some(rv)
+    }
+}
+
+fn note_key(number: u8) -> (Note, i32) {
+    // MIDI note 60 is middle C, C4
+    (Note::from_index(number as usize), number as i32 / 12 - 1)
+}
+
+fn note_event(kind: NoteEventKind, number: u8, velocity: u8) -> NoteEvent {
+    let (note, octave) = note_key(number);
+    NoteEvent { kind, note, octave, velocity: velocity as f32 / 127.0 }
+}
+
+
+/// Connection to a MIDI input port, collecting messages received on midir's thread.
+pub struct MidiIn {
+    conn: Option<(String, MidiInputConnection<()>)>,
+    tx: Sender<MidiMessage>,
+    rx: Receiver<MidiMessage>,
+}
+
+impl MidiIn {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self { conn: None, tx, rx }
+    }
+
+    pub fn list_ports() -> anyhow::Result<Vec<String>> {
+        let input = MidiInput::new(CLIENT_NAME)?;
+        let names = input.ports()
+            .iter()
+            .map(|p| input.port_name(p))
+            .collect::<Result<_, _>>()?;
+        Ok(names)
+    }
+
+    /// Connects to the port whose name contains `port`, or with index `port` in [`Self::list_ports`].
+    /// Returns the full name of the port.
+    pub fn connect(&mut self, port: &str) -> anyhow::Result<String> {
+        let input = MidiInput::new(CLIENT_NAME)?;
+        let ports = input.ports();
+        let mut found = None;
+        for (i, p) in ports.iter().enumerate() {
+            let name = input.port_name(p)?;
+            if port.parse() == Ok(i) || name.contains(port) {
+                found = Some((p.clone(), name));
+                break;
+            }
+        }
+        let Some((p, name)) = found
+        else {
+            bail!("no MIDI input port matching \"{port}\"");
+        };
+
+        let tx = self.tx.clone();
+        let conn = input
+            .connect(&p, "doris-in", move |_, bytes, _| Self::forward(&tx, bytes), ())
+            .map_err(|e| anyhow!("failed to connect to \"{name}\": {e}"))?;
+        self.conn = Some((name.clone(), conn));
+        Ok(name)
+    }
+
+    /// Opens a virtual input port named `name` that other programs (or tests) can send to.
+    #[cfg(unix)]
+    pub fn connect_virtual(&mut self, name: &str) -> anyhow::Result<()> {
+        use midir::os::unix::VirtualInput;
+
+        let input = MidiInput::new(CLIENT_NAME)?;
+        let tx = self.tx.clone();
+        let conn = input
+            .create_virtual(name, move |_, bytes, _| Self::forward(&tx, bytes), ())
+            .map_err(|e| anyhow!("failed to create virtual port \"{name}\": {e}"))?;
+        self.conn = Some((name.to_string(), conn));
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect_virtual(&mut self, _name: &str) -> anyhow::Result<()> {
+        bail!("virtual MIDI ports are not supported on this platform")
+    }
+
+    pub fn disconnect(&mut self) -> Option<String> {
+        self.conn.take().map(|(name, conn)| {
+            conn.close();
+            name
+        })
+    }
+
+    pub fn get_messages(&mut self) -> Vec<MidiMessage> {
+        self.rx.try_iter().collect()
+    }
+
+    fn forward(tx: &Sender<MidiMessage>, bytes: &[u8]) {
+        if let Some(msg) = MidiMessage::parse(bytes) {
+            let _ = tx.send(msg);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_channel_messages() {
+        assert_eq!(
+            MidiMessage::parse(&[0x90, 60, 127]),
+            Some(MidiMessage::Note(NoteEvent { kind: NoteEventKind::Start, note: Note::C, octave: 4, velocity: 1.0 }))
+        );
+        // note on with zero velocity is a note off, on any channel
+        assert_eq!(
+            MidiMessage::parse(&[0x93, 69, 0]),
+            Some(MidiMessage::Note(NoteEvent { kind: NoteEventKind::Stop, note: Note::A, octave: 4, velocity: 0.0 }))
+        );
+        assert_eq!(MidiMessage::parse(&[0xB0, 1, 127]), Some(MidiMessage::ModWheel(1.0)));
+        assert_eq!(
+            MidiMessage::parse(&[0xA0, 61, 127]),
+            Some(MidiMessage::Aftertouch { key: Some((Note::CSharp, 4)), pressure: 1.0 })
+        );
+        assert_eq!(MidiMessage::parse(&[0xD0, 0]), Some(MidiMessage::Aftertouch { key: None, pressure: 0.0 }));
+        assert_eq!(MidiMessage::parse(&[0xB0, 64, 0]), Some(MidiMessage::Sustain(false)));
+        assert_eq!(MidiMessage::parse(&[0xE0, 0, 64]), Some(MidiMessage::PitchBend(0.0)));
+        assert_eq!(MidiMessage::parse(&[0xE0, 0, 0]), Some(MidiMessage::PitchBend(-1.0)));
+        assert_eq!(MidiMessage::parse(&[0xF8]), None);
+    }
+
+    #[test]
+    #[ignore = "needs an ALSA sequencer"]
+    fn receives_from_virtual_port() {
+        use midir::MidiOutput;
+
+        let mut midi = MidiIn::new();
+        midi.connect_virtual("doris-test").unwrap();
+
+        let output = MidiOutput::new("doris-test-out").unwrap();
+        let port = output.ports().into_iter()
+            .find(|p| output.port_name(p).unwrap().contains("doris-test"))
+            .unwrap();
+        let mut conn = output.connect(&port, "loopback").unwrap();
+        conn.send(&[0x90, 60, 100]).unwrap();
+        conn.send(&[0xB0, 64, 127]).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        let msgs = midi.get_messages();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[1], MidiMessage::Sustain(true));
+    }
+}
@@ -1,10 +1,16 @@
-use std::{collections::HashMap, fs::OpenOptions, sync::Arc};
+use std::{collections::{BTreeMap, HashMap, HashSet}, fmt, sync::Arc};
 
+use anyhow::bail;
 use serde::{Serialize, Deserialize};
+use strum::EnumDiscriminants;
 
 use fundsp::hacker::*;
 
-#[derive(Serialize, Deserialize)]
+use crate::voice::VoiceConfig;
+use crate::yaml;
+
+#[derive(Serialize, Deserialize, Clone, EnumDiscriminants)]
+#[strum_discriminants(name(PatchNodeKind), derive(strum::EnumIter))]
 #[serde(tag="op")]
 pub enum PatchNode {
     Constant { c: f32 },
@@ -28,9 +34,25 @@ pub enum PatchNode {
     // Effects
     FlangerSin { strength: f32, min_delay: f32, max_delay: f32, sin_freq: f32 },
     ADSR { attack: f32, decay: f32, sustain: f32, release: f32 },
+    /// Stereo reverb (two inputs, two outputs); `room_size` in metres, `time` to -60 dB in seconds, `damping` in 0...1.
+    Reverb { room_size: f32, time: f32, damping: f32, wet: f32 },
+    Chorus { separation: f32, variation: f32, mod_freq: f32 },
+    /// Feedback delay; with `sync` set, `time` is in beats of the track's tempo rather than seconds.
+    Delay { time: f32, #[serde(default)] sync: bool, feedback: f32, wet: f32 },
+    Phaser { feedback: f32, rate: f32 },
 
     // Filters
-    // TODO
+    // Parameters left out of the YAML become extra input ports, after the audio on port 0, in the
+    // order they are declared (e.g. a lowpass with only `q` set reads its cutoff from port 1).
+    Lowpass { cutoff: Option<f32>, q: Option<f32> },
+    Highpass { cutoff: Option<f32>, q: Option<f32> },
+    Bandpass { cutoff: Option<f32>, q: Option<f32> },
+    Notch { cutoff: Option<f32>, q: Option<f32> },
+    /// Bell EQ, boosting or cutting around `cutoff` by the amplitude `gain` (1 leaves it flat).
+    Peak { cutoff: Option<f32>, q: Option<f32>, gain: Option<f32> },
+    LowShelf { cutoff: Option<f32>, q: Option<f32>, gain: Option<f32> },
+    HighShelf { cutoff: Option<f32>, q: Option<f32>, gain: Option<f32> },
+    Moog { cutoff: Option<f32>, resonance: Option<f32> },
 
     // Maths
     SumChannels,
@@ -40,8 +62,192 @@ pub enum PatchNode {
     // Pan { balance: f32 },
 }
 
+impl PatchNodeKind {
+    /// A node of this kind with typical parameters, to start editing from.
+    pub fn default_node(self) -> PatchNode {
+        match self {
+            Self::Constant => PatchNode::Constant { c: 1.0 },
+            Self::Sine => PatchNode::Sine,
+            Self::Saw => PatchNode::Saw,
+            Self::Square => PatchNode::Square,
+            Self::SpecifiedSine => PatchNode::SpecifiedSine { freq: 220.0 },
+            Self::SpecifiedSaw => PatchNode::SpecifiedSaw { freq: 220.0 },
+            Self::SpecifiedSquare => PatchNode::SpecifiedSquare { freq: 220.0 },
+            Self::Sample => PatchNode::Sample { path: "kick.wav".into(), looped: false },
+            Self::WhiteNoise => PatchNode::WhiteNoise,
+            Self::PinkNoise => PatchNode::PinkNoise,
+            Self::BrownNoise => PatchNode::BrownNoise,
+            Self::FlangerSin => PatchNode::FlangerSin { strength: 0.5, min_delay: 0.005, max_delay: 0.01, sin_freq: 0.1 },
+            Self::ADSR => PatchNode::ADSR { attack: 0.1, decay: 0.1, sustain: 0.5, release: 0.1 },
+            Self::Reverb => PatchNode::Reverb { room_size: 10.0, time: 1.0, damping: 0.5, wet: 0.2 },
+            Self::Chorus => PatchNode::Chorus { separation: 0.015, variation: 0.005, mod_freq: 0.5 },
+            Self::Delay => PatchNode::Delay { time: 0.5, sync: true, feedback: 0.5, wet: 0.3 },
+            Self::Phaser => PatchNode::Phaser { feedback: 0.5, rate: 0.1 },
+            Self::Lowpass => PatchNode::Lowpass { cutoff: Some(1000.0), q: Some(0.7) },
+            Self::Highpass => PatchNode::Highpass { cutoff: Some(1000.0), q: Some(0.7) },
+            Self::Bandpass => PatchNode::Bandpass { cutoff: Some(1000.0), q: Some(0.7) },
+            Self::Notch => PatchNode::Notch { cutoff: Some(1000.0), q: Some(0.7) },
+            Self::Peak => PatchNode::Peak { cutoff: Some(1000.0), q: Some(0.7), gain: Some(2.0) },
+            Self::LowShelf => PatchNode::LowShelf { cutoff: Some(200.0), q: Some(0.7), gain: Some(2.0) },
+            Self::HighShelf => PatchNode::HighShelf { cutoff: Some(5000.0), q: Some(0.7), gain: Some(2.0) },
+            Self::Moog => PatchNode::Moog { cutoff: Some(1000.0), resonance: Some(0.3) },
+            Self::SumChannels => PatchNode::SumChannels,
+            Self::MultChannels => PatchNode::MultChannels,
+            Self::Mux => PatchNode::Mux,
+        }
+    }
+}
+
 impl PatchNode {
-    pub fn add_to_net(&self, net: &mut Net) -> anyhow::Result<NodeId> {
+    pub fn kind(&self) -> PatchNodeKind {
+        self.into()
+    }
+
+    /// The node's parameters by name, each as YAML text (`null` for a filter parameter read from a port).
+    pub fn params(&self) -> Vec<(String, String)> {
+        let Ok(serde_yaml::Value::Mapping(fields)) = serde_yaml::to_value(self)
+        else {
+            return Vec::new();
+        };
+        fields.into_iter()
+            .filter_map(|(k, v)| {
+                let k = k.as_str()?.to_string();
+                let v = match v {
+                    // parameters are f32, so print them as such rather than widened (0.01, not 0.009999999776)
+                    serde_yaml::Value::Number(n) if n.is_f64() => (n.as_f64()? as f32).to_string(),
+                    v => serde_yaml::to_string(&v).ok()?.trim_end().to_string(),
+                };
+                (k != "op").then_some((k, v))
+            })
+            .collect()
+    }
+
+    /// A copy of the node with the parameter `name` set from the YAML text `value`.
+    pub fn with_param(&self, name: &str, value: &str) -> anyhow::Result<Self> {
+        let serde_yaml::Value::Mapping(mut fields) = serde_yaml::to_value(self)?
+        else {
+            bail!("node has no parameters");
+        };
+        if name == "op" || !fields.contains_key(name) {
+            bail!("no parameter \"{name}\"");
+        }
+        let value: serde_yaml::Value = serde_yaml::from_str(value)?;
+        fields.insert(name.into(), value);
+        serde_yaml::from_value(serde_yaml::Value::Mapping(fields))
+            .map_err(|e| anyhow::anyhow!("invalid {name}: {e}"))
+    }
+
+    /// Number of input and output channels of the unit this node adds to a net.
+    pub fn arity(&self) -> (usize, usize) {
+        match self {
+            Self::Constant { .. } => (0, 1),
+
+            // Oscillators
+            Self::Sine | Self::Saw | Self::Square => (1, 1),
+            Self::SpecifiedSine { .. } | Self::SpecifiedSaw { .. } | Self::SpecifiedSquare { .. } => (0, 1),
+
+            // Sample
+            Self::Sample { .. } => (0, 1),
+
+            // Noise
+            Self::WhiteNoise | Self::PinkNoise | Self::BrownNoise => (0, 1),
+
+            // Effects
+            Self::FlangerSin { .. } => (1, 1),
+            Self::ADSR { .. } => (1, 1),
+            Self::Reverb { .. } => (2, 2),
+            Self::Chorus { .. } | Self::Delay { .. } | Self::Phaser { .. } => (1, 1),
+
+            // Filters
+            Self::Lowpass { .. } | Self::Highpass { .. } | Self::Bandpass { .. } | Self::Notch { .. }
+                | Self::Peak { .. } | Self::LowShelf { .. } | Self::HighShelf { .. } | Self::Moog { .. } => {
+                let free = self.filter_params().iter().filter(|p| p.is_none()).count();
+                (1 + free, 1)
+            },
+
+            // Maths
+            Self::SumChannels | Self::MultChannels | Self::Mux => (2, 1),
+        }
+    }
+
+    /// Rejects parameter values the underlying units can't work with.
+    pub fn check(&self) -> anyhow::Result<()> {
+        match self {
+            Self::Sample { path, .. } if !std::path::Path::new(path).is_file() => {
+                bail!("sample file \"{path}\" not found")
+            },
+            Self::FlangerSin { min_delay, max_delay, .. } if !(*min_delay > 0.0 && min_delay <= max_delay) => {
+                bail!("flanger delays must satisfy 0 < min_delay <= max_delay")
+            },
+            Self::ADSR { attack, decay, release, .. } if *attack < 0.0 || *decay < 0.0 || *release < 0.0 => {
+                bail!("envelope times can't be negative")
+            },
+            Self::ADSR { sustain, .. } if !(0.0..=1.0).contains(sustain) => {
+                bail!("sustain must be in 0...1")
+            },
+            Self::Reverb { room_size, time, .. } if *room_size <= 0.0 || *time <= 0.0 => {
+                bail!("reverb room_size and time must be positive")
+            },
+            Self::Reverb { damping, wet, .. } if !(0.0..=1.0).contains(damping) || !(0.0..=1.0).contains(wet) => {
+                bail!("reverb damping and wet must be in 0...1")
+            },
+            Self::Chorus { separation, variation, .. } if *separation < 0.0 || *variation < 0.0 => {
+                bail!("chorus separation and variation can't be negative")
+            },
+            Self::Delay { time, .. } if *time <= 0.0 => {
+                bail!("delay time must be positive")
+            },
+            Self::Delay { wet, .. } if !(0.0..=1.0).contains(wet) => {
+                bail!("delay wet must be in 0...1")
+            },
+            // cutoff is always a filter's first parameter
+            Self::Lowpass { .. } | Self::Highpass { .. } | Self::Bandpass { .. } | Self::Notch { .. }
+                | Self::Peak { .. } | Self::LowShelf { .. } | Self::HighShelf { .. } | Self::Moog { .. }
+                if self.filter_params()[0].is_some_and(|c| c <= 0.0) => {
+                bail!("filter cutoff must be positive")
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Fixed values of a filter's parameters, in port order.
+    fn filter_params(&self) -> Vec<Option<f32>> {
+        match self {
+            Self::Lowpass { cutoff, q } | Self::Highpass { cutoff, q } | Self::Bandpass { cutoff, q }
+                | Self::Notch { cutoff, q } => vec![*cutoff, *q],
+            Self::Peak { cutoff, q, gain } | Self::LowShelf { cutoff, q, gain } | Self::HighShelf { cutoff, q, gain } => {
+                vec![*cutoff, *q, *gain]
+            },
+            Self::Moog { cutoff, resonance } => vec![*cutoff, *resonance],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Wraps a filter taking audio plus every parameter as inputs so that parameters with a fixed
+    /// value are fed by constants, leaving the rest as inputs.
+    fn parameterised(filter: Box<dyn AudioUnit>, params: &[Option<f32>]) -> Net {
+        let free = params.iter().filter(|p| p.is_none()).count();
+        let mut net = Net::new(1 + free, 1);
+        let id = net.push(filter);
+        net.set_source(id, 0, Source::Global(0));
+        let mut port = 1;
+        for (i, param) in params.iter().enumerate() {
+            match param {
+                Some(v) => {
+                    let c = net.push(Box::new(constant(*v)));
+                    net.set_source(id, i + 1, Source::Local(c, 0));
+                }
+                None => {
+                    net.set_source(id, i + 1, Source::Global(port));
+                    port += 1;
+                }
+            }
+        }
+        net.set_output_source(0, Source::Local(id, 0));
+        net
+    }
+
+    pub fn add_to_net(&self, net: &mut Net, ctx: &mut NetContext) -> anyhow::Result<NodeId> {
         let rv = match self {
             Self::Constant { c }            => { net.push(Box::new(constant(*c))) },
 
@@ -55,8 +261,7 @@ impl PatchNode {
 
             // Sample
             Self::Sample { path, looped }           => {
-                let wave = Wave::load(path)?;
-                let wave = Arc::new(wave);
+                let wave = ctx.waves.load(path)?;
                 net.push(Box::new(
                     wavech(&wave, 0, if *looped { Some(0) } else { None })
                 ))
@@ -81,12 +286,36 @@ impl PatchNode {
             Self::ADSR { attack, decay, sustain, release } => {
                 net.push(Box::new( adsr_live(*attack, *decay, *sustain, *release) ))
             }
+            Self::Reverb { room_size, time, damping, wet } => {
+                net.push(Box::new(
+                    (multipass::<U2>() * (1.0 - *wet)) & (reverb_stereo(*room_size, *time, *damping) * *wet)
+                ))
+            }
+            Self::Chorus { separation, variation, mod_freq } => {
+                net.push(Box::new( chorus(0, *separation, *variation, *mod_freq) ))
+            }
+            Self::Delay { time, sync, feedback: amount, wet } => {
+                let time = if *sync { time * 60.0 / ctx.bpm } else { *time };
+                net.push(Box::new(
+                    (pass() * (1.0 - *wet)) & (feedback(delay(time) * *amount) * *wet)
+                ))
+            }
+            Self::Phaser { feedback, rate } => {
+                let rate = *rate;
+                net.push(Box::new( phaser(*feedback, move |t| sin_hz(rate, t) * 0.5 + 0.5) ))
+            }
 
             // Filters
-            // TODO
+            Self::Lowpass { .. }            => { net.push(Box::new(Self::parameterised(Box::new(lowpass()), &self.filter_params()))) },
+            Self::Highpass { .. }           => { net.push(Box::new(Self::parameterised(Box::new(highpass()), &self.filter_params()))) },
+            Self::Bandpass { .. }           => { net.push(Box::new(Self::parameterised(Box::new(bandpass()), &self.filter_params()))) },
+            Self::Notch { .. }              => { net.push(Box::new(Self::parameterised(Box::new(notch()), &self.filter_params()))) },
+            Self::Peak { .. }               => { net.push(Box::new(Self::parameterised(Box::new(bell()), &self.filter_params()))) },
+            Self::LowShelf { .. }           => { net.push(Box::new(Self::parameterised(Box::new(lowshelf()), &self.filter_params()))) },
+            Self::HighShelf { .. }          => { net.push(Box::new(Self::parameterised(Box::new(highshelf()), &self.filter_params()))) },
+            Self::Moog { .. }               => { net.push(Box::new(Self::parameterised(Box::new(moog()), &self.filter_params()))) },
 
             // Self::Pan { balance }           => { net.push(Box::new(pan(*balance))) },
-            _ => { todo!() }
         };
 
         Ok(rv)
@@ -94,15 +323,107 @@ impl PatchNode {
 }
 
 
-#[derive(Serialize, Deserialize)]
+/// Problem found in a patch graph by [`Patch::validate`]. `edge` is an index into the patch's edges.
+#[derive(Debug)]
+pub enum PatchDiagnostic {
+    UnknownNode { edge: usize, name: String },
+    InvalidNode { name: String, reason: String },
+    BadPort { edge: usize, endpoint: String },
+    ArityMismatch { edge: usize, node: String, channel: usize, channels: usize, is_input: bool },
+    ReservedName { name: String },
+    MisusedReserved { edge: usize, name: String },
+    /// An edge into a port another edge already feeds; a port takes one source.
+    SharedSink { edge: usize, port: String, first: usize },
+    Cycle { nodes: Vec<String> },
+    UnconnectedOutput,
+    DanglingNode { name: String },
+}
+
+impl PatchDiagnostic {
+    /// Errors stop a net being built; anything else is only a warning.
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Self::DanglingNode { .. })
+    }
+}
+
+impl fmt::Display for PatchDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownNode { edge, name } => write!(f, "edge {edge}: no node named \"{name}\""),
+            Self::InvalidNode { name, reason } => write!(f, "\"{name}\": {reason}"),
+            Self::BadPort { edge, endpoint } => write!(f, "edge {edge}: invalid port \"{endpoint}\" (expected name or name:channel)"),
+            Self::ArityMismatch { edge, node, channel, channels, is_input } => {
+                let dir = if *is_input { "input" } else { "output" };
+                write!(f, "edge {edge}: \"{node}\" has no {dir} channel {channel} (it has {channels})")
+            },
+            Self::ReservedName { name } => write!(f, "node name \"{name}\" is reserved"),
+            Self::MisusedReserved { edge, name } => {
+                if name == OUTPUT {
+                    write!(f, "edge {edge}: \"{name}\" can only be connected to")
+                }
+                else {
+                    write!(f, "edge {edge}: \"{name}\" can only be connected from")
+                }
+            },
+            Self::SharedSink { edge, port, first } => write!(f, "edge {edge}: \"{port}\" is already fed by edge {first}"),
+            Self::Cycle { nodes } => write!(f, "cycle: {}", nodes.join("-->")),
+            Self::UnconnectedOutput => write!(f, "nothing is connected to \"{OUTPUT}\""),
+            Self::DanglingNode { name } => write!(f, "\"{name}\" does not reach \"{OUTPUT}\""),
+        }
+    }
+}
+
+
+/// Samples loaded from disk, keyed by path, so each file is only read once.
+#[derive(Default)]
+pub struct WaveCache {
+    waves: HashMap<String, Arc<Wave>>,
+}
+
+impl WaveCache {
+    pub fn load(&mut self, path: &str) -> anyhow::Result<Arc<Wave>> {
+        if let Some(wave) = self.waves.get(path) {
+            return Ok(wave.clone());
+        }
+        let wave = Arc::new(Wave::load(path)?);
+        self.waves.insert(path.to_string(), wave.clone());
+        Ok(wave)
+    }
+}
+
+
+/// Settings and resources from outside a patch that its nodes can depend on.
+pub struct NetContext {
+    pub bpm: f32,
+    pub waves: WaveCache,
+}
+
+impl NetContext {
+    pub fn new(bpm: f32) -> Self {
+        Self { bpm, waves: WaveCache::default() }
+    }
+}
+
+
+/// Sources every patch can read without declaring them as nodes; the index is the net's global input.
+/// `mod` is the controller's mod wheel, `vel` how hard the note was struck and `press` how hard it
+/// is held down (aftertouch), all 0...1.
+pub const INPUTS: [&str; 5] = ["freq", "ctl", "mod", "vel", "press"];
+
+/// Sink for the patch's (mono) output.
+pub const OUTPUT: &str = "out";
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Patch {
-    nodes: HashMap<String, PatchNode>,
-    edges: Vec<(String, String)>
+    nodes: BTreeMap<String, PatchNode>,
+    edges: Vec<(String, String)>,
+    #[serde(default)]
+    voices: VoiceConfig,
 }
 
 impl Patch {
     pub fn new() -> Self {
-        let mut nodes = HashMap::new();
+        let mut nodes = BTreeMap::new();
         nodes.insert("osc1".into(), PatchNode::Saw);
         nodes.insert("flanger".into(), PatchNode::FlangerSin { strength: 0.5, min_delay: 0.005, max_delay: 0.01, sin_freq: 0.1 });
         nodes.insert("adsr".into(), PatchNode::ADSR { attack: 1.0, decay: 0.5, sustain: 0.5, release: 0.5 });
@@ -118,43 +439,150 @@ impl Patch {
         edges.push(("mux".into(), "add:0".into()));
         edges.push(("kick".into(), "add:1".into()));
         edges.push(("add".into(), "out".into()));
-        Self { nodes, edges }
+        Self { nodes, edges, voices: VoiceConfig::default() }
     }
 
-    pub fn from_file(p: &str) -> anyhow::Result<Self> {
-        let f = OpenOptions::new().read(true).open(p)?;
-        let rv = serde_yaml::from_reader(f)?;
-        Ok(rv)
+    /// A saw shaped by an envelope: the smallest patch that plays notes, for new patches to start from.
+    pub fn basic() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert("osc".into(), PatchNode::Saw);
+        nodes.insert("env".into(), PatchNode::ADSR { attack: 0.01, decay: 0.1, sustain: 0.7, release: 0.2 });
+        nodes.insert("vca".into(), PatchNode::Mux);
+        let edges = vec![
+            ("freq".into(), "osc".into()),
+            ("ctl".into(), "env".into()),
+            ("osc".into(), "vca:0".into()),
+            ("env".into(), "vca:1".into()),
+            ("vca".into(), OUTPUT.into()),
+        ];
+        Self { nodes, edges, voices: VoiceConfig::default() }
     }
 
-    fn get_branches(end: String, edges: &Vec<(String, String)>) -> anyhow::Result<Vec<Vec<String>>> {
-        let mut rv = Vec::new();
+    /// A patch playing the WAV file at `path` once through, as it is.
+    pub fn sample(path: &str) -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert("sample".into(), PatchNode::Sample { path: path.into(), looped: false });
+        let edges = vec![("sample".into(), OUTPUT.into())];
+        Self { nodes, edges, voices: VoiceConfig::default() }
+    }
 
-        for (i, o) in edges.iter() {
-            let (i, _) = Self::parse_node_name(i)?;
-            let (o, _) = Self::parse_node_name(o)?;
+    pub fn voices(&self) -> &VoiceConfig {
+        &self.voices
+    }
 
-            if o == end {
-                let child_branches = Self::get_branches(i.clone(), edges)?;
-                if child_branches.len() == 0 {
-                    rv.push(vec![i.clone(), o.clone()]);
-                }
-                for mut child in child_branches.into_iter() {
-                    child.push(o.clone());
-                    rv.push(child);
-                }
+    pub fn nodes(&self) -> &BTreeMap<String, PatchNode> {
+        &self.nodes
+    }
+
+    /// Connections as (source, sink) ports, each a node name with an optional `:channel`.
+    pub fn edges(&self) -> &[(String, String)] {
+        &self.edges
+    }
+
+    pub fn add_node(&mut self, name: &str, node: PatchNode) -> anyhow::Result<()> {
+        Self::check_name(name)?;
+        if self.nodes.contains_key(name) {
+            bail!("there is already a node named \"{name}\"");
+        }
+        self.nodes.insert(name.to_string(), node);
+        Ok(())
+    }
+
+    /// Replaces the node named `name`, keeping its connections.
+    pub fn set_node(&mut self, name: &str, node: PatchNode) -> anyhow::Result<()> {
+        match self.nodes.get_mut(name) {
+            Some(n) => *n = node,
+            None => bail!("no node named \"{name}\""),
+        }
+        Ok(())
+    }
+
+    /// Removes the node named `name` and every connection to or from it.
+    pub fn remove_node(&mut self, name: &str) -> Option<PatchNode> {
+        let node = self.nodes.remove(name)?;
+        self.edges.retain(|(src, snk)| Self::port_node(src) != name && Self::port_node(snk) != name);
+        Some(node)
+    }
+
+    /// Renames a node, keeping its connections.
+    pub fn rename_node(&mut self, from: &str, to: &str) -> anyhow::Result<()> {
+        Self::check_name(to)?;
+        if self.nodes.contains_key(to) {
+            bail!("there is already a node named \"{to}\"");
+        }
+        let node = self.nodes.remove(from).ok_or_else(|| anyhow::anyhow!("no node named \"{from}\""))?;
+        self.nodes.insert(to.to_string(), node);
+        for port in self.edges.iter_mut().flat_map(|(src, snk)| [src, snk]) {
+            if Self::port_node(port) == from {
+                *port = format!("{to}{}", &port[from.len()..]);
             }
         }
+        Ok(())
+    }
 
-        Ok(rv)
+    /// Connects port `src` to port `snk`, replacing whatever was connected to `snk` before, as a port
+    /// only takes one source.
+    pub fn connect(&mut self, src: &str, snk: &str) -> anyhow::Result<()> {
+        let src_port = Self::parse_node_name(&src.to_string())?;
+        let snk_port = Self::parse_node_name(&snk.to_string())?;
+        self.edges.retain(|(_, s)| Self::parse_node_name(s).ok().as_ref() != Some(&snk_port));
+        self.edges.push((Self::port_name(src_port), Self::port_name(snk_port)));
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self, src: &str, snk: &str) -> anyhow::Result<()> {
+        let src_port = Self::parse_node_name(&src.to_string())?;
+        let snk_port = Self::parse_node_name(&snk.to_string())?;
+        let n = self.edges.len();
+        self.edges.retain(|(a, b)| {
+            Self::parse_node_name(a).ok().as_ref() != Some(&src_port) || Self::parse_node_name(b).ok().as_ref() != Some(&snk_port)
+        });
+        if self.edges.len() == n {
+            bail!("\"{src}\" is not connected to \"{snk}\"");
+        }
+        Ok(())
+    }
+
+    fn check_name(name: &str) -> anyhow::Result<()> {
+        if name.is_empty() || name.contains(|c: char| c == ':' || c.is_whitespace()) {
+            bail!("\"{name}\" is not a valid node name");
+        }
+        if INPUTS.contains(&name) || name == OUTPUT {
+            bail!("node name \"{name}\" is reserved");
+        }
+        Ok(())
+    }
+
+    /// The node part of a port.
+    fn port_node(port: &str) -> &str {
+        port.split_once(':').map_or(port, |(n, _)| n)
+    }
+
+    /// A port written the way the YAML usually has it, leaving out channel 0.
+    fn port_name((node, channel): (String, usize)) -> String {
+        if channel == 0 { node } else { format!("{node}:{channel}") }
+    }
+
+    /// How long a note rings on after it is released: the longest release of the patch's envelopes.
+    pub fn release_time(&self) -> f32 {
+        self.nodes.values()
+            .filter_map(|n| match n {
+                PatchNode::ADSR { release, .. } => Some(*release),
+                _ => None,
+            })
+            .fold(0.0, f32::max)
+    }
+
+    pub fn from_file(p: &str) -> anyhow::Result<Self> {
+        yaml::load(p)
     }
 
-    pub fn branch_reprs(&self) -> anyhow::Result<Vec<String>> {
-        let branches = Self::get_branches("out".into(), &self.edges)?;
-        Ok(branches.into_iter().map(|b| b.join("-->")).collect())
+    pub fn to_file(&self, p: &str) -> anyhow::Result<()> {
+        yaml::save(self, p)
     }
 
-    fn parse_node_name(n: &String) -> anyhow::Result<(String, usize)> {
+    /// Splits a port into its node name and channel.
+    pub fn parse_node_name(n: &String) -> anyhow::Result<(String, usize)> {
         let rv = match n.split_once(":") {
             Some((n, ch)) => (n.to_string(), ch.parse()?),
             None => (n.to_string(), 0)
@@ -162,58 +590,182 @@ impl Patch {
         Ok(rv)
     }
 
-    pub fn create_net(&self) -> anyhow::Result<Net> {
-        let mut net = Net::new(2, 1);
-        net.pass_through(0, 0);
+    /// Checks the graph for anything that would stop it building into a working net.
+    pub fn validate(&self) -> Vec<PatchDiagnostic> {
+        let mut rv = Vec::new();
+
+        let names: Vec<_> = self.nodes.keys().collect();
+        for name in names.iter() {
+            if INPUTS.contains(&name.as_str()) || name.as_str() == OUTPUT {
+                rv.push(PatchDiagnostic::ReservedName { name: name.to_string() });
+            }
+            if let Err(e) = self.nodes[*name].check() {
+                rv.push(PatchDiagnostic::InvalidNode { name: name.to_string(), reason: e.to_string() });
+            }
+        }
+
+        let mut links = Vec::new();
+        let mut sinks: HashMap<(String, usize), usize> = HashMap::new();
+        let mut output_connected = false;
+        for (edge, (src, snk)) in self.edges.iter().enumerate() {
+            let Ok((src, src_ch)) = Self::parse_node_name(src)
+            else {
+                rv.push(PatchDiagnostic::BadPort { edge, endpoint: src.clone() });
+                continue;
+            };
+            let Ok((snk, snk_ch)) = Self::parse_node_name(snk)
+            else {
+                rv.push(PatchDiagnostic::BadPort { edge, endpoint: snk.clone() });
+                continue;
+            };
+
+            let mut ok = true;
+            if src == OUTPUT {
+                rv.push(PatchDiagnostic::MisusedReserved { edge, name: src.clone() });
+                ok = false;
+            }
+            else if INPUTS.contains(&src.as_str()) {
+                if src_ch != 0 {
+                    rv.push(PatchDiagnostic::ArityMismatch { edge, node: src.clone(), channel: src_ch, channels: 1, is_input: false });
+                    ok = false;
+                }
+            }
+            else if let Some(node) = self.nodes.get(&src) {
+                let (_, outputs) = node.arity();
+                if src_ch >= outputs {
+                    rv.push(PatchDiagnostic::ArityMismatch { edge, node: src.clone(), channel: src_ch, channels: outputs, is_input: false });
+                    ok = false;
+                }
+            }
+            else {
+                rv.push(PatchDiagnostic::UnknownNode { edge, name: src.clone() });
+                ok = false;
+            }
+
+            if INPUTS.contains(&snk.as_str()) {
+                rv.push(PatchDiagnostic::MisusedReserved { edge, name: snk.clone() });
+                ok = false;
+            }
+            else if snk == OUTPUT {
+                if snk_ch != 0 {
+                    rv.push(PatchDiagnostic::ArityMismatch { edge, node: snk.clone(), channel: snk_ch, channels: 1, is_input: true });
+                    ok = false;
+                }
+                output_connected = true;
+            }
+            else if let Some(node) = self.nodes.get(&snk) {
+                let (inputs, _) = node.arity();
+                if snk_ch >= inputs {
+                    rv.push(PatchDiagnostic::ArityMismatch { edge, node: snk.clone(), channel: snk_ch, channels: inputs, is_input: true });
+                    ok = false;
+                }
+            }
+            else {
+                rv.push(PatchDiagnostic::UnknownNode { edge, name: snk.clone() });
+                ok = false;
+            }
+
+            if ok {
+                match sinks.get(&(snk.clone(), snk_ch)) {
+                    Some(&first) => rv.push(PatchDiagnostic::SharedSink { edge, port: self.edges[edge].1.clone(), first }),
+                    None => {
+                        sinks.insert((snk.clone(), snk_ch), edge);
+                    },
+                }
+                links.push((src, snk));
+            }
+        }
+
+        if !output_connected {
+            rv.push(PatchDiagnostic::UnconnectedOutput);
+        }
+
+        if let Some(nodes) = Self::find_cycle(&names, &links) {
+            rv.push(PatchDiagnostic::Cycle { nodes });
+        }
+
+        // walk back from the output to find everything that contributes to it
+        let mut reached = HashSet::new();
+        let mut stack = vec![OUTPUT.to_string()];
+        while let Some(n) = stack.pop() {
+            for (src, snk) in links.iter() {
+                if *snk == n && reached.insert(src.clone()) {
+                    stack.push(src.clone());
+                }
+            }
+        }
+        for name in names.iter() {
+            if !reached.contains(name.as_str()) {
+                rv.push(PatchDiagnostic::DanglingNode { name: name.to_string() });
+            }
+        }
+
+        rv
+    }
+
+    fn find_cycle(names: &[&String], links: &[(String, String)]) -> Option<Vec<String>> {
+        // depth-first search, a node seen again while still on the path closes a cycle
+        fn visit(n: &str, links: &[(String, String)], path: &mut Vec<String>, done: &mut HashSet<String>) -> Option<Vec<String>> {
+            if let Some(i) = path.iter().position(|p| p == n) {
+                let mut cycle = path[i..].to_vec();
+                cycle.push(n.to_string());
+                return Some(cycle);
+            }
+            if done.contains(n) {
+                return None;
+            }
+            path.push(n.to_string());
+            for (src, snk) in links.iter() {
+                if src == n && let Some(cycle) = visit(snk, links, path, done) {
+                    return Some(cycle);
+                }
+            }
+            path.pop();
+            done.insert(n.to_string());
+            None
+        }
+
+        let mut done = HashSet::new();
+        for name in names.iter() {
+            if let Some(cycle) = visit(name, links, &mut Vec::new(), &mut done) {
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    pub fn create_net(&self, ctx: &mut NetContext) -> anyhow::Result<Net> {
+        let errors: Vec<_> = self.validate()
+            .into_iter()
+            .filter(|d| d.is_error())
+            .map(|d| d.to_string())
+            .collect();
+        if !errors.is_empty() {
+            anyhow::bail!("invalid patch: {}", errors.join("; "));
+        }
+
+        let mut net = Net::new(INPUTS.len(), 1);
 
         let mut nodes_by_id = HashMap::new();
         for (node_name, node) in self.nodes.iter() {
-            let node_id = node.add_to_net(&mut net)?;
+            let node_id = node.add_to_net(&mut net, ctx)?;
             nodes_by_id.insert(node_name.clone(), node_id);
         }
 
         for (src, snk) in self.edges.iter() {
             let (src, src_ch) = Self::parse_node_name(src)?;
             let (snk, snk_ch) = Self::parse_node_name(snk)?;
-            let src_id = nodes_by_id.get(&src).cloned();
-            let snk_id = nodes_by_id.get(&snk).cloned();
-            // eprintln!("{src} -> {snk}");
-
-            match (src.as_str(), snk.as_str()) {
-                ("freq", "out") => {
-                    net.pass_through(0, 0);
-                    // eprintln!("pass freq:0 to out:0");
-                },
-                ("ctl", "out") => {
-                    net.pass_through(1, 0);
-                    // eprintln!("pass ctl:0 to out:0");
-                },
-                ("freq", _) => {
-                    if let Some(snk_id) = snk_id {
-                        net.set_source(snk_id, snk_ch, Source::Global(0));
-                        // eprintln!("pass freq:0 to {snk}:{snk_ch}");
-                    } // TODO: otherwise error
-                },
-                ("ctl", _) => {
-                    if let Some(snk_id) = snk_id {
-                        net.set_source(snk_id, snk_ch, Source::Global(1));
-                        // eprintln!("pass ctl:0 to {snk}:{snk_ch}");
-                    } // TODO: otherwise error
-                },
-                (_, "out") => {
-                    if let Some(src_id) = src_id {
-                        net.pipe_output(src_id);
-                        // eprintln!("pass {src}: to out:");
-                    }
-                },
-                _ => {
-                    if let Some((src_id, snk_id)) = src_id.zip(snk_id) {
-                        net.set_source(snk_id, snk_ch, Source::Local(src_id, src_ch));
-                        // eprintln!("pass {src}:{src_ch} to {snk}:{snk_ch}");
-                    }
-                },
-            }
 
+            let source = match INPUTS.iter().position(|i| *i == src) {
+                Some(i) => Source::Global(i),
+                None => Source::Local(nodes_by_id[&src], src_ch),
+            };
+            if snk == OUTPUT {
+                net.set_output_source(0, source);
+            }
+            else {
+                net.set_source(nodes_by_id[&snk], snk_ch, source);
+            }
         }
         Ok(net)
     }
@@ -238,3 +790,160 @@ impl Patch {
     //     Ok((freq_net, ctl_net))
     // }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use strum::IntoEnumIterator;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+    use crate::voice::{StealPolicy, VoiceMode};
+
+    fn example(kind: PatchNodeKind, sample_path: &str) -> PatchNode {
+        match kind {
+            PatchNodeKind::Sample => PatchNode::Sample { path: sample_path.into(), looped: false },
+            // leave some filter parameters to ports
+            PatchNodeKind::Lowpass => PatchNode::Lowpass { cutoff: Some(1000.0), q: None },
+            PatchNodeKind::Highpass => PatchNode::Highpass { cutoff: None, q: Some(0.7) },
+            PatchNodeKind::Bandpass => PatchNode::Bandpass { cutoff: None, q: None },
+            PatchNodeKind::HighShelf => PatchNode::HighShelf { cutoff: None, q: None, gain: None },
+            PatchNodeKind::Moog => PatchNode::Moog { cutoff: None, resonance: Some(0.3) },
+            kind => kind.default_node(),
+        }
+    }
+
+    #[test]
+    fn every_node_matches_its_arity() {
+        let dir = TestDir::new("patch-arity");
+        let sample_path = &dir.file("sample.wav");
+        Wave::render(44100.0, 0.01, &mut sine_hz(440.0)).save_wav16(sample_path).unwrap();
+
+        let mut ctx = NetContext::new(120.0);
+        for kind in PatchNodeKind::iter() {
+            let node = example(kind, sample_path);
+            node.check().unwrap_or_else(|e| panic!("{kind:?}: {e}"));
+
+            let mut net = Net::new(0, 0);
+            let id = node.add_to_net(&mut net, &mut ctx).unwrap();
+            assert_eq!(node.arity(), (net.inputs_in(id), net.outputs_in(id)), "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn round_trips_through_yaml() {
+        let nodes = PatchNodeKind::iter()
+            .map(|kind| (format!("{kind:?}").to_lowercase(), example(kind, "kick.wav")))
+            .collect();
+        let edges = vec![("freq".into(), "sine".into()), ("sine".into(), "out".into())];
+        let voices = VoiceConfig { max_voices: 2, steal: StealPolicy::SameNote, mode: VoiceMode::Portamento { time: 0.1 } };
+        let patch = Patch { nodes, edges, voices };
+
+        let dir = TestDir::new("patch-round-trip");
+        let path = &dir.file("patch.yaml");
+        patch.to_file(path).unwrap();
+        let saved = std::fs::read_to_string(path).unwrap();
+        let loaded = Patch::from_file(path).unwrap();
+        assert_eq!(saved, serde_yaml::to_string(&loaded).unwrap());
+        assert_eq!(saved, serde_yaml::to_string(&patch).unwrap());
+    }
+
+    #[test]
+    fn diagnoses_bad_graphs() {
+        let diagnose = |edges: &[(&str, &str)]| {
+            let mut patch = Patch::basic();
+            patch.edges = edges.iter().map(|(a, b)| (a.to_string(), b.to_string())).collect();
+            patch.validate().iter().map(|d| d.to_string()).collect::<Vec<_>>()
+        };
+        let chain = [("freq", "osc"), ("ctl", "env"), ("osc", "vca:0"), ("env", "vca:1"), ("vca", "out")];
+        assert!(diagnose(&chain).is_empty());
+
+        let mut edges = chain.to_vec();
+        edges.push(("lfo", "vca:1"));
+        assert_eq!(diagnose(&edges), ["edge 5: no node named \"lfo\""]);
+
+        edges[5] = ("osc:x", "vca");
+        assert_eq!(diagnose(&edges), ["edge 5: invalid port \"osc:x\" (expected name or name:channel)"]);
+
+        edges[5] = ("osc", "vca:2");
+        assert_eq!(diagnose(&edges), ["edge 5: \"vca\" has no input channel 2 (it has 2)"]);
+        edges[5] = ("osc:1", "env");
+        assert_eq!(diagnose(&edges), ["edge 5: \"osc\" has no output channel 1 (it has 1)"]);
+
+        edges[5] = ("env", "vca:0");
+        assert_eq!(diagnose(&edges), ["edge 5: \"vca:0\" is already fed by edge 2"]);
+
+        // vca feeds itself through the envelope's input
+        edges[5] = ("vca", "env");
+        assert_eq!(diagnose(&edges), [
+            "edge 5: \"env\" is already fed by edge 1",
+            "cycle: env-->vca-->env",
+        ]);
+
+        let diagnostics = diagnose(&chain[..4]);
+        assert_eq!(diagnostics[0], "nothing is connected to \"out\"");
+        assert!(diagnostics[1..].iter().all(|d| d.ends_with("does not reach \"out\"")));
+    }
+
+    #[test]
+    fn peak_boosts_and_cuts() {
+        let level = |gain: f32| {
+            let patch: Patch = serde_yaml::from_str(&format!("
+                nodes:
+                  tone: {{op: SpecifiedSine, freq: 1000}}
+                  eq: {{op: Peak, cutoff: 1000, q: 1, gain: {gain}}}
+                edges: [[tone, eq], [eq, out]]
+            ")).unwrap();
+            let mut net = patch.create_net(&mut NetContext::new(120.0)).unwrap();
+            let mut out = [0.0];
+            let mut peak: f32 = 0.0;
+            for i in 0..44100 {
+                net.tick(&[0.0; INPUTS.len()], &mut out);
+                if i > 22050 {
+                    peak = peak.max(out[0].abs());
+                }
+            }
+            peak
+        };
+        assert!((level(2.0) - 2.0).abs() < 0.05, "boost {}", level(2.0));
+        assert!((level(0.5) - 0.5).abs() < 0.05, "cut {}", level(0.5));
+    }
+
+    #[test]
+    fn basic_patch_is_valid() {
+        let patch = Patch::basic();
+        assert!(patch.validate().is_empty());
+        patch.create_net(&mut NetContext::new(120.0)).unwrap();
+    }
+
+    #[test]
+    fn edits_graph() {
+        let mut patch = Patch::new();
+        assert!(patch.add_node("freq", PatchNode::Sine).is_err());
+        assert!(patch.add_node("osc1", PatchNode::Sine).is_err());
+        patch.add_node("lp", PatchNodeKind::Lowpass.default_node()).unwrap();
+
+        // a port takes one source, so connecting again replaces the old edge
+        patch.connect("osc1", "lp").unwrap();
+        patch.connect("lp:0", "flanger:0").unwrap();
+        assert!(patch.edges().contains(&("lp".into(), "flanger".into())));
+        assert!(!patch.edges().contains(&("osc1".into(), "flanger".into())));
+
+        patch.rename_node("mux", "vca").unwrap();
+        assert!(patch.edges().contains(&("flanger".into(), "vca:0".into())));
+        assert!(patch.edges().contains(&("adsr".into(), "vca:1".into())));
+        assert!(patch.rename_node("vca", "adsr").is_err());
+
+        let lp = patch.nodes()["lp"].with_param("q", "null").unwrap();
+        assert_eq!(lp.arity(), (2, 1));
+        assert!(lp.with_param("q", "loud").is_err());
+        assert!(lp.with_param("gain", "1").is_err());
+        patch.set_node("lp", lp).unwrap();
+        assert!(patch.nodes()["lp"].params().contains(&("cutoff".into(), "1000".into())));
+
+        patch.disconnect("lp", "flanger").unwrap();
+        assert!(patch.disconnect("lp", "flanger").is_err());
+        patch.remove_node("kick").unwrap();
+        assert!(patch.edges().iter().all(|(src, snk)| src != "kick" && !snk.starts_with("kick")));
+    }
+}
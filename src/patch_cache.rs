@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+
+use fundsp::hacker::*;
+
+use crate::patch::{NetContext, Patch, PatchNode};
+
+
+/// Compiled patch nets, built once and cloned for every note that plays them.
+pub struct PatchCache {
+    ctx: NetContext,
+    nets: HashMap<String, Net>,
+}
+
+impl PatchCache {
+    pub fn new(bpm: f32) -> Self {
+        Self { ctx: NetContext::new(bpm), nets: HashMap::new() }
+    }
+
+    /// Returns a fresh copy of the net for `patch`, compiling it under `key` if it isn't cached yet.
+    pub fn get(&mut self, key: &str, patch: &Patch) -> anyhow::Result<Net> {
+        if let Some(net) = self.nets.get(key) {
+            return Ok(net.clone());
+        }
+        let net = patch.create_net(&mut self.ctx)?;
+        self.nets.insert(key.to_string(), net.clone());
+        Ok(net)
+    }
+
+    /// Seconds it takes `patch`'s samples to play through once; looped ones don't count.
+    pub fn sample_length(&mut self, patch: &Patch) -> anyhow::Result<f64> {
+        let mut length: f64 = 0.0;
+        for node in patch.nodes().values() {
+            if let PatchNode::Sample { path, looped: false } = node {
+                length = length.max(self.ctx.waves.load(path)?.duration());
+            }
+        }
+        Ok(length)
+    }
+
+    /// Drops the compiled net for `key`, e.g. after the patch it was built from changes.
+    pub fn invalidate(&mut self, key: &str) {
+        self.nets.remove(key);
+    }
+
+    /// Drops every compiled net if the tempo changes, as tempo-synced nodes depend on it.
+    pub fn set_bpm(&mut self, bpm: f32) {
+        if self.ctx.bpm != bpm {
+            self.ctx.bpm = bpm;
+            self.nets.clear();
+        }
+    }
+
+    /// Drops every compiled net and loaded sample.
+    pub fn clear(&mut self) {
+        self.ctx = NetContext::new(self.ctx.bpm);
+        self.nets.clear();
+    }
+}
@@ -0,0 +1,690 @@
+use std::collections::HashMap;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, MouseEvent};
+use ratatui::symbols::Marker;
+use ratatui::widgets::canvas::{Canvas, Line as CanvasLine};
+use ratatui::widgets::{Block, Borders, Clear, Paragraph, Widget, Wrap};
+use ratatui::prelude::*;
+use strum::IntoEnumIterator;
+
+use crate::envelope_editor::EnvelopeEditor;
+use crate::event_handler::EventHandler;
+use crate::frame_renderable::FrameRenderable;
+use crate::patch::{self, Patch, PatchNodeKind};
+
+
+/// What the text typed into the editor's prompt is for.
+enum PromptKind {
+    Add,
+    Rename,
+    Connect,
+    Disconnect,
+    Param(String),
+}
+
+struct Prompt {
+    kind: PromptKind,
+    text: String,
+    /// What Tab is completing node kinds from, while cycling through them.
+    stem: Option<String>,
+}
+
+impl Prompt {
+    fn new(kind: PromptKind, text: String) -> Self {
+        Self { kind, text, stem: None }
+    }
+
+    fn label(&self) -> &str {
+        match &self.kind {
+            PromptKind::Add => "add $kind [$name]",
+            PromptKind::Rename => "rename to",
+            PromptKind::Connect => "connect $from[:ch] $to[:ch]",
+            PromptKind::Disconnect => "disconnect $from[:ch] $to[:ch]",
+            PromptKind::Param(name) => name,
+        }
+    }
+
+    /// Completes the node kind at the start of the text, moving on to the next match each time.
+    fn complete_kind(&mut self) {
+        let (word, rest) = self.text.split_once(' ').unwrap_or((&self.text, ""));
+        let stem = self.stem.get_or_insert_with(|| word.to_lowercase());
+        let kinds: Vec<_> = PatchNodeKind::iter()
+            .map(|k| format!("{k:?}"))
+            .filter(|k| k.to_lowercase().starts_with(stem.as_str()))
+            .collect();
+        let next = kinds.iter()
+            .position(|k| k == word)
+            .map_or(0, |i| (i + 1) % kinds.len());
+        if let Some(kind) = kinds.get(next) {
+            self.text = if rest.is_empty() { kind.clone() } else { format!("{kind} {rest}") };
+        }
+    }
+}
+
+
+/// Editor for a patch's graph: nodes are laid out in columns from the inputs on the left to `out`
+/// on the right, with wires for the edges, and the selected node's details alongside.
+pub struct PatchEditor {
+    patch: Patch,
+    /// Name of the patch in the track, if it is one of the track's.
+    name: Option<String>,
+    selected: String,
+    param: usize,
+    prompt: Option<Prompt>,
+    /// Why the last edit failed.
+    message: Option<String>,
+    /// Editor for the selected node, if it is an envelope.
+    envelope: Option<EnvelopeEditor>,
+    changed: bool,
+    finished: bool,
+}
+
+impl PatchEditor {
+    const BOX_WIDTH: u16 = 20;
+    const PANEL_WIDTH: u16 = 44;
+    const ENVELOPE_HEIGHT: u16 = 14;
+
+    pub fn new(patch: Patch, name: Option<String>) -> Self {
+        let mut rv = Self {
+            patch,
+            name,
+            selected: String::new(),
+            param: 0,
+            prompt: None,
+            message: None,
+            envelope: None,
+            changed: false,
+            finished: false,
+        };
+        rv.select_first();
+        rv.sync_envelope();
+        rv
+    }
+
+    /// Starts editing `patch`, dropping anything half done on the last one.
+    pub fn set_patch(&mut self, patch: Patch, name: Option<String>) {
+        *self = Self::new(patch, name);
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The patch as edited, if it has changed since last asked.
+    pub fn take_changed(&mut self) -> Option<Patch> {
+        if self.changed {
+            self.changed = false;
+            Some(self.patch.clone())
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn set_unfinished(&mut self) {
+        self.finished = false;
+    }
+
+    fn select_first(&mut self) {
+        self.selected = self.columns().into_iter().flatten().next().unwrap_or_default();
+        self.param = 0;
+    }
+
+    /// Names in the graph, reserved inputs and `out` included, in columns by how many nodes lie
+    /// between them and the inputs.
+    fn columns(&self) -> Vec<Vec<String>> {
+        let links: Vec<_> = self.patch.edges()
+            .iter()
+            .filter_map(|(src, snk)| {
+                let (src, _) = Patch::parse_node_name(src).ok()?;
+                let (snk, _) = Patch::parse_node_name(snk).ok()?;
+                Some((src, snk))
+            })
+            .collect();
+
+        let mut names: Vec<String> = patch::INPUTS.iter()
+            .filter(|i| links.iter().any(|(src, _)| src == *i))
+            .map(|i| i.to_string())
+            .collect();
+        names.extend(self.patch.nodes().keys().cloned());
+
+        // longest path from the inputs; a cycle can't push a node further than there are nodes
+        let mut depth: HashMap<&str, usize> = names.iter().map(|n| (n.as_str(), 0)).collect();
+        for _ in 0..names.len() {
+            let mut moved = false;
+            for (src, snk) in links.iter() {
+                if let (Some(&d), Some(&e)) = (depth.get(src.as_str()), depth.get(snk.as_str()))
+                    && e < d + 1 {
+                    depth.insert(snk.as_str(), d + 1);
+                    moved = true;
+                }
+            }
+            if !moved {
+                break;
+            }
+        }
+
+        let n = depth.values().max().map_or(0, |d| d + 1);
+        let mut columns = vec![Vec::new(); n];
+        for name in names.iter() {
+            columns[depth[name.as_str()]].push(name.clone());
+        }
+        // a cycle spreads its nodes out, leaving gaps between them
+        columns.retain(|c| !c.is_empty());
+        columns.push(vec![patch::OUTPUT.to_string()]);
+
+        // order each column by where the nodes feeding it sit, to keep wires short
+        let mut row: HashMap<String, f32> = HashMap::new();
+        for column in columns.iter_mut() {
+            let key = |name: &String| {
+                let rows: Vec<f32> = links.iter()
+                    .filter(|(_, snk)| snk == name)
+                    .filter_map(|(src, _)| row.get(src).copied())
+                    .collect();
+                if rows.is_empty() { f32::MAX } else { rows.iter().sum::<f32>() / rows.len() as f32 }
+            };
+            column.sort_by(|a, b| key(a).total_cmp(&key(b)));
+            for (i, name) in column.iter().enumerate() {
+                row.insert(name.clone(), i as f32);
+            }
+        }
+        columns
+    }
+
+    /// Keeps the envelope editor showing the selected node, if it is an envelope.
+    fn sync_envelope(&mut self) {
+        let node = self.patch.nodes().get(&self.selected);
+        let shown = match (&mut self.envelope, node) {
+            (Some(envelope), Some(node)) => envelope.set_node(node),
+            (None, Some(node)) => {
+                self.envelope = EnvelopeEditor::new(node);
+                self.envelope.is_some()
+            },
+            (_, None) => false,
+        };
+        if !shown {
+            self.envelope = None;
+        }
+    }
+
+    /// Puts any change made in the envelope editor into the patch.
+    fn apply_envelope(&mut self) {
+        if let Some(node) = self.envelope.as_mut().and_then(|e| e.take_changed()) {
+            let name = self.selected.clone();
+            self.edit(|p| p.set_node(&name, node));
+        }
+    }
+
+    fn is_reserved(name: &str) -> bool {
+        patch::INPUTS.contains(&name) || name == patch::OUTPUT
+    }
+
+    /// Moves the selection `dx` columns across, or `dy` rows within its column.
+    fn move_selection(&mut self, dx: isize, dy: isize) {
+        let columns = self.columns();
+        let Some((c, r)) = columns.iter()
+            .enumerate()
+            .find_map(|(c, col)| col.iter().position(|n| *n == self.selected).map(|r| (c, r)))
+        else {
+            self.select_first();
+            return;
+        };
+        // the nearest column that way with anything in it, or stay put
+        let target = (c as isize + dx).clamp(0, columns.len() as isize - 1) as usize;
+        let Some(col) = (if dx < 0 { columns[target..=c].iter().find(|col| !col.is_empty()) }
+            else { columns[c..=target].iter().rev().find(|col| !col.is_empty()) })
+        else {
+            return;
+        };
+        let r = (r as isize + dy).clamp(0, col.len() as isize - 1) as usize;
+        self.selected = col[r].clone();
+        self.param = 0;
+    }
+
+    /// Applies `edit` to a copy of the patch, keeping the result if it succeeds.
+    fn edit(&mut self, edit: impl FnOnce(&mut Patch) -> anyhow::Result<()>) -> bool {
+        let mut patch = self.patch.clone();
+        match edit(&mut patch) {
+            Ok(()) => {
+                self.patch = patch;
+                self.changed = true;
+                self.message = None;
+                true
+            },
+            Err(e) => {
+                self.message = Some(e.to_string());
+                false
+            },
+        }
+    }
+
+    fn open_prompt(&mut self, kind: PromptKind) {
+        let selected = self.selected.clone();
+        let text = match &kind {
+            PromptKind::Add => String::new(),
+            PromptKind::Rename => selected.clone(),
+            PromptKind::Connect | PromptKind::Disconnect if selected == patch::OUTPUT => String::new(),
+            PromptKind::Connect | PromptKind::Disconnect => format!("{selected} "),
+            PromptKind::Param(name) => {
+                let params = self.patch.nodes().get(&selected).map(|n| n.params()).unwrap_or_default();
+                params.into_iter().find(|(k, _)| k == name).map(|(_, v)| v).unwrap_or_default()
+            },
+        };
+        if matches!(kind, PromptKind::Rename | PromptKind::Param(_)) && Self::is_reserved(&selected) {
+            self.message = Some(format!("\"{selected}\" is built in"));
+            return;
+        }
+        self.prompt = Some(Prompt::new(kind, text));
+    }
+
+    fn run_prompt(&mut self, prompt: Prompt) {
+        let words: Vec<String> = prompt.text.split_whitespace().map(String::from).collect();
+        let selected = self.selected.clone();
+        match prompt.kind {
+            PromptKind::Add => {
+                let Some(kind) = words.first()
+                    .and_then(|w| PatchNodeKind::iter().find(|k| format!("{k:?}").eq_ignore_ascii_case(w)))
+                else {
+                    self.message = Some(format!("unknown node kind \"{}\"; Tab completes", prompt.text.trim()));
+                    return;
+                };
+                let name = match words.get(1) {
+                    Some(name) => name.clone(),
+                    None => {
+                        let stem = format!("{kind:?}").to_lowercase();
+                        (1..).map(|i| format!("{stem}{i}")).find(|n| !self.patch.nodes().contains_key(n)).unwrap_or(stem)
+                    },
+                };
+                if self.edit(|p| p.add_node(&name, kind.default_node())) {
+                    self.selected = name;
+                    self.param = 0;
+                }
+            },
+            PromptKind::Rename => {
+                let to = prompt.text.trim().to_string();
+                if to != selected && self.edit(|p| p.rename_node(&selected, &to)) {
+                    self.selected = to;
+                }
+            },
+            PromptKind::Connect | PromptKind::Disconnect => {
+                let [src, snk] = words.as_slice()
+                else {
+                    self.message = Some("expected two ports, e.g. osc1 mux:1".into());
+                    return;
+                };
+                match prompt.kind {
+                    PromptKind::Connect => self.edit(|p| p.connect(src, snk)),
+                    _ => self.edit(|p| p.disconnect(src, snk)),
+                };
+            },
+            PromptKind::Param(name) => {
+                let Some(node) = self.patch.nodes().get(&selected)
+                else {
+                    return;
+                };
+                match node.with_param(&name, &prompt.text) {
+                    Ok(node) => { self.edit(|p| p.set_node(&selected, node)); },
+                    Err(e) => self.message = Some(e.to_string()),
+                }
+            },
+        }
+    }
+
+    fn handle_prompt_key(&mut self, kev: KeyEvent) {
+        let Some(prompt) = self.prompt.as_mut()
+        else {
+            return;
+        };
+        match kev.code {
+            KeyCode::Enter => {
+                if let Some(prompt) = self.prompt.take() {
+                    self.run_prompt(prompt);
+                }
+            },
+            KeyCode::Esc => self.prompt = None,
+            KeyCode::Tab if matches!(prompt.kind, PromptKind::Add) => prompt.complete_kind(),
+            KeyCode::Backspace => {
+                prompt.text.pop();
+                prompt.stem = None;
+            },
+            KeyCode::Char(c) => {
+                prompt.text.push(c);
+                prompt.stem = None;
+            },
+            _ => (),
+        }
+    }
+
+    /// Box of each name on screen, within `area`.
+    fn boxes(&self, columns: &[Vec<String>], area: Rect) -> HashMap<String, Rect> {
+        let mut rv = HashMap::new();
+        let pitch = area.width / columns.len().max(1) as u16;
+        let width = Self::BOX_WIDTH.min(pitch.saturating_sub(2)).max(3);
+        for (c, column) in columns.iter().enumerate() {
+            let x = area.x + c as u16 * pitch + (pitch.saturating_sub(width)) / 2;
+            let mut y = area.y;
+            for name in column.iter() {
+                // a line per port, or for the kind and each parameter, whichever is more
+                let (ports, lines) = self.patch.nodes()
+                    .get(name)
+                    .map_or((1, 1), |n| (n.arity().0.max(n.arity().1), 1 + n.params().len()));
+                let height = 2 + ports.max(lines) as u16;
+                let rect = Rect::new(x, y, width, height).intersection(area);
+                rv.insert(name.clone(), rect);
+                y += height + 1;
+            }
+        }
+        rv
+    }
+
+    fn draw_graph(&self, frame: &mut Frame, area: Rect) {
+        let columns = self.columns();
+        let boxes = self.boxes(&columns, area);
+
+        // wires first, so the boxes sit on top of their ends
+        let mut wires = Vec::new();
+        for (src, snk) in self.patch.edges() {
+            let (Ok((src, src_ch)), Ok((snk, snk_ch))) = (Patch::parse_node_name(src), Patch::parse_node_name(snk))
+            else {
+                continue;
+            };
+            let (Some(a), Some(b)) = (boxes.get(&src), boxes.get(&snk))
+            else {
+                continue;
+            };
+            let colour = if src == self.selected || snk == self.selected { Color::Yellow } else { Color::DarkGray };
+            let from = ((a.x + a.width) as f64, (a.y + 1 + src_ch as u16) as f64);
+            let to = (b.x as f64 - 1.0, (b.y + 1 + snk_ch as u16) as f64);
+            wires.push((from, to, colour));
+        }
+        let height = area.height as f64;
+        Canvas::default()
+            .x_bounds([0.0, area.width as f64])
+            .y_bounds([0.0, height])
+            .marker(Marker::Braille)
+            .paint(move |ctx| {
+                // cell coordinates, from the top, to the canvas's, from the bottom
+                let point = |(x, y): (f64, f64)| (x - area.x as f64 + 0.5, height - (y - area.y as f64) - 0.5);
+                for (from, to, color) in wires.iter() {
+                    let (x1, y1) = point(*from);
+                    let (x2, y2) = point(*to);
+                    let mid = if x2 > x1 { (x1 + x2) / 2.0 } else { x1 };
+                    ctx.draw(&CanvasLine { x1, y1, x2: mid, y2: y1, color: *color });
+                    ctx.draw(&CanvasLine { x1: mid, y1, x2: mid, y2, color: *color });
+                    ctx.draw(&CanvasLine { x1: mid, y1: y2, x2, y2, color: *color });
+                }
+            })
+            .render(area, frame.buffer_mut());
+
+        for (name, rect) in boxes.iter() {
+            let lines = match self.patch.nodes().get(name) {
+                Some(node) => {
+                    let mut lines = vec![Line::from(format!("{:?}", node.kind()))];
+                    lines.extend(node.params().into_iter().map(|(k, v)| Line::from(format!("{k} {v}")).dark_gray()));
+                    lines
+                },
+                None if name == patch::OUTPUT => vec![Line::from("output")],
+                None => vec![Line::from("input")],
+            };
+            let mut style = if Self::is_reserved(name) { Style::new().dim() } else { Style::new() };
+            if *name == self.selected {
+                style = style.yellow().bold();
+            }
+            let block = Block::new().borders(Borders::ALL).border_style(style).title(name.as_str());
+            Clear.render(*rect, frame.buffer_mut());
+            Paragraph::new(lines)
+                .block(block)
+                .render(*rect, frame.buffer_mut());
+        }
+    }
+
+    fn draw_panel(&self, frame: &mut Frame, area: Rect) {
+        let mut lines = Vec::new();
+        match self.patch.nodes().get(&self.selected) {
+            Some(node) => {
+                lines.push(Line::from(format!("{} ({:?})", self.selected, node.kind())).bold());
+                for (i, (k, v)) in node.params().into_iter().enumerate() {
+                    let line = Line::from(format!("  {k}: {v}"));
+                    lines.push(if i == self.param { line.reversed() } else { line });
+                }
+            },
+            None => lines.push(Line::from(format!("{} (built in)", self.selected)).bold()),
+        }
+
+        let mut inputs = Vec::new();
+        let mut outputs = Vec::new();
+        for (src, snk) in self.patch.edges() {
+            if Patch::parse_node_name(snk).is_ok_and(|(n, _)| n == self.selected) {
+                inputs.push(Line::from(format!("  {snk} <- {src}")));
+            }
+            if Patch::parse_node_name(src).is_ok_and(|(n, _)| n == self.selected) {
+                outputs.push(Line::from(format!("  {src} -> {snk}")));
+            }
+        }
+        if !inputs.is_empty() {
+            lines.push(Line::from("inputs").dim());
+            lines.extend(inputs);
+        }
+        if !outputs.is_empty() {
+            lines.push(Line::from("outputs").dim());
+            lines.extend(outputs);
+        }
+
+        lines.push(Line::from(""));
+        for d in self.patch.validate() {
+            let line = Line::from(d.to_string());
+            lines.push(if d.is_error() { line.red() } else { line.yellow() });
+        }
+        if let Some(message) = &self.message {
+            lines.push(Line::from(message.as_str()).red());
+        }
+
+        let block = Block::new().borders(Borders::ALL).dim();
+        let inner = block.inner(area);
+        block.render(area, frame.buffer_mut());
+        let envelope_height = if self.envelope.is_some() { Self::ENVELOPE_HEIGHT } else { 0 };
+        let [body, envelope_area, prompt_area] = Layout::new(Direction::Vertical, vec![
+            Constraint::Min(0),
+            Constraint::Length(envelope_height),
+            Constraint::Length(2),
+        ]).areas(inner);
+        if let Some(envelope) = &self.envelope {
+            envelope.draw_into(frame, envelope_area);
+        }
+        Paragraph::new(lines).wrap(Wrap { trim: false }).render(body, frame.buffer_mut());
+
+        if let Some(prompt) = &self.prompt {
+            Paragraph::new(vec![
+                Line::from(prompt.label()).dark_gray(),
+                Line::from(vec![Span::styled("> ", Style::new().dim()), Span::raw(&prompt.text)]),
+            ]).render(prompt_area, frame.buffer_mut());
+            let cx = prompt_area.x + 2 + prompt.text.chars().count() as u16;
+            frame.set_cursor_position((cx.min(prompt_area.right().saturating_sub(1)), prompt_area.y + 1));
+        }
+    }
+}
+
+
+impl EventHandler for PatchEditor {
+    fn handle_key(&mut self, kev: KeyEvent) -> anyhow::Result<bool> {
+        if kev.kind != KeyEventKind::Press {
+            return Ok(false);
+        }
+        if self.prompt.is_some() {
+            self.handle_prompt_key(kev);
+            return Ok(false);
+        }
+        if let Some(envelope) = self.envelope.as_mut() && envelope.is_focused() {
+            envelope.handle_key(kev)?;
+            self.apply_envelope();
+            return Ok(false);
+        }
+
+        let n_params = self.patch.nodes().get(&self.selected).map_or(0, |n| n.params().len());
+        match kev.code {
+            KeyCode::Esc => self.finished = true,
+            KeyCode::Up => self.move_selection(0, -1),
+            KeyCode::Down => self.move_selection(0, 1),
+            KeyCode::Left => self.move_selection(-1, 0),
+            KeyCode::Right => self.move_selection(1, 0),
+            KeyCode::Tab if n_params > 0 => self.param = (self.param + 1) % n_params,
+            KeyCode::BackTab if n_params > 0 => self.param = (self.param + n_params - 1) % n_params,
+            KeyCode::Enter => {
+                let param = self.patch.nodes().get(&self.selected)
+                    .and_then(|n| n.params().into_iter().nth(self.param))
+                    .map(|(k, _)| k);
+                match param {
+                    Some(name) => self.open_prompt(PromptKind::Param(name)),
+                    None => self.message = Some(format!("\"{}\" has no parameters", self.selected)),
+                }
+            },
+            KeyCode::Char('e') => match self.envelope.as_mut() {
+                Some(envelope) => envelope.focus(),
+                None => self.message = Some(format!("\"{}\" isn't an envelope", self.selected)),
+            },
+            KeyCode::Char('a') => self.open_prompt(PromptKind::Add),
+            KeyCode::Char('r') => self.open_prompt(PromptKind::Rename),
+            KeyCode::Char('c') => self.open_prompt(PromptKind::Connect),
+            KeyCode::Char('x') => self.open_prompt(PromptKind::Disconnect),
+            KeyCode::Char('d') | KeyCode::Delete => {
+                let name = self.selected.clone();
+                if Self::is_reserved(&name) {
+                    self.message = Some(format!("\"{name}\" is built in"));
+                }
+                else if self.edit(|p| p.remove_node(&name).map(|_| ()).ok_or_else(|| anyhow::anyhow!("no node named \"{name}\""))) {
+                    self.select_first();
+                }
+            },
+            _ => (),
+        }
+        self.sync_envelope();
+        Ok(false)
+    }
+
+    fn handle_mouse(&mut self, mev: MouseEvent) -> anyhow::Result<bool> {
+        if let Some(envelope) = self.envelope.as_mut() {
+            envelope.handle_mouse(mev)?;
+            self.apply_envelope();
+        }
+        Ok(false)
+    }
+}
+
+
+impl FrameRenderable for PatchEditor {
+    fn draw_into(&self, frame: &mut Frame, area: Rect) {
+        let title = match &self.name {
+            Some(name) => format!(" patch \"{name}\" "),
+            None => " live patch ".to_string(),
+        };
+        let block = Block::new()
+            .borders(Borders::ALL)
+            .title(title)
+            .title_bottom(
+                Line::from("arrows select; tab/enter edit param; e envelope; a add; r rename; d delete; c connect; x disconnect; esc back.")
+                .centered()
+            );
+        let inner = block.inner(area);
+        block.render(area, frame.buffer_mut());
+
+        let [graph, panel] = Layout::new(Direction::Horizontal, vec![
+            Constraint::Min(0),
+            Constraint::Length(Self::PANEL_WIDTH),
+        ]).areas(inner);
+        self.draw_graph(frame, graph);
+        self.draw_panel(frame, panel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::KeyModifiers;
+
+    use super::*;
+    use crate::patch::PatchNode;
+
+    fn type_keys(editor: &mut PatchEditor, keys: &str) {
+        for c in keys.chars() {
+            let code = match c {
+                '\n' => KeyCode::Enter,
+                '\t' => KeyCode::Tab,
+                '\x08' => KeyCode::Backspace,
+                c => KeyCode::Char(c),
+            };
+            editor.handle_key(KeyEvent::new(code, KeyModifiers::NONE)).unwrap();
+        }
+    }
+
+    #[test]
+    fn lays_out_and_edits() {
+        let patch: Patch = serde_yaml::from_str("
+            nodes:
+              osc: {op: Saw}
+              env: {op: ADSR, attack: 0.01, decay: 0.1, sustain: 0.5, release: 0.2}
+              mux: {op: Mux}
+            edges: [[freq, osc], [osc, 'mux:0'], [ctl, env], [env, 'mux:1'], [mux, out]]
+        ").unwrap();
+        let mut editor = PatchEditor::new(patch, Some("lead".into()));
+        assert_eq!(editor.columns(), [vec!["freq", "ctl"], vec!["osc", "env"], vec!["mux"], vec!["out"]]);
+        assert_eq!(editor.selected, "freq");
+        assert!(editor.take_changed().is_none());
+
+        // a lowpass between the oscillator and the mux, named for its kind
+        type_keys(&mut editor, "alow\t\n");
+        assert_eq!(editor.selected, "lowpass1");
+        type_keys(&mut editor, "c\x08:0 mux:0\n");
+        type_keys(&mut editor, &format!("c{}osc lowpass1\n", "\x08".repeat(9)));
+        assert_eq!(editor.columns()[2], ["lowpass1"]);
+        assert_eq!(editor.columns()[3], ["mux"]);
+
+        type_keys(&mut editor, "\t\n\x08\x08\x08\x082\n");
+        let patch = editor.take_changed().unwrap();
+        assert!(patch.nodes()["lowpass1"].params().contains(&("q".into(), "2".into())));
+        assert!(patch.validate().iter().all(|d| !d.is_error()));
+
+        type_keys(&mut editor, &format!("r{}lp\n", "\x08".repeat(8)));
+        assert!(editor.patch.edges().contains(&("lp".into(), "mux".into())));
+        type_keys(&mut editor, "cnowhere mux\n");
+        assert!(editor.message.is_some());
+        type_keys(&mut editor, "d");
+        assert!(!editor.patch.nodes().contains_key("lp"));
+        assert!(editor.message.is_none());
+
+        // the envelope takes the keys once focused, and its edits go into the patch
+        type_keys(&mut editor, "e");
+        assert!(editor.message.is_some());
+        editor.selected = "env".into();
+        editor.sync_envelope();
+        type_keys(&mut editor, "es");
+        editor.handle_key(KeyEvent::new(KeyCode::Up, KeyModifiers::NONE)).unwrap();
+        let patch = editor.take_changed().unwrap();
+        assert!(matches!(patch.nodes()["env"], PatchNode::ADSR { sustain, .. } if (sustain - 0.55).abs() < 1e-6));
+        editor.handle_key(KeyEvent::new(KeyCode::Esc, KeyModifiers::NONE)).unwrap();
+        assert!(!editor.is_finished());
+    }
+
+    #[test]
+    fn moves_through_a_cycle() {
+        let patch: Patch = serde_yaml::from_str("
+            nodes:
+              a: {op: Lowpass}
+              b: {op: Lowpass}
+            edges: [[freq, a], [a, b], [b, 'a:1'], [b, out]]
+        ").unwrap();
+        let mut editor = PatchEditor::new(patch, None);
+        assert!(editor.columns().iter().all(|c| !c.is_empty()));
+
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        let mut seen = Vec::new();
+        for code in [KeyCode::Right; 4].into_iter().chain([KeyCode::Left; 4]) {
+            editor.handle_key(key(code)).unwrap();
+            seen.push(editor.selected.clone());
+        }
+        assert_eq!(seen.last().unwrap(), "freq");
+        assert!(seen.iter().any(|n| n == patch::OUTPUT));
+    }
+}
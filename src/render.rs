@@ -0,0 +1,173 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use fundsp::funutd::Rnd;
+use fundsp::hacker::*;
+use hound::{WavSpec, WavWriter};
+
+use crate::patch_cache::PatchCache;
+use crate::track::Track;
+use crate::voice;
+
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum BitDepth {
+    /// What renders are written as unless asked otherwise, from the command line or the TUI.
+    #[default]
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl FromStr for BitDepth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "16" => Ok(Self::Int16),
+            "24" => Ok(Self::Int24),
+            "32" => Ok(Self::Float32),
+            _ => Err(anyhow!("unsupported bit depth \"{s}\" (expected 16, 24 or 32)")),
+        }
+    }
+}
+
+impl BitDepth {
+    fn spec(&self, sample_rate: f64) -> WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            Self::Int16 => (16, hound::SampleFormat::Int),
+            Self::Int24 => (24, hound::SampleFormat::Int),
+            Self::Float32 => (32, hound::SampleFormat::Float),
+        };
+        WavSpec { channels: 2, sample_rate: sample_rate as u32, bits_per_sample, sample_format }
+    }
+}
+
+
+/// Stereo WAV file written one frame at a time.
+pub struct WavSink {
+    writer: WavWriter<BufWriter<File>>,
+    depth: BitDepth,
+}
+
+impl WavSink {
+    pub fn create(path: &str, sample_rate: f64, depth: BitDepth) -> anyhow::Result<Self> {
+        let writer = WavWriter::create(path, depth.spec(sample_rate))?;
+        Ok(Self { writer, depth })
+    }
+
+    pub fn write_frame(&mut self, left: f32, right: f32) -> anyhow::Result<()> {
+        for s in [left, right] {
+            let s = s.clamp(-1.0, 1.0);
+            match self.depth {
+                BitDepth::Int16 => self.writer.write_sample((s * i16::MAX as f32) as i16)?,
+                BitDepth::Int24 => self.writer.write_sample((s * 8_388_607.0) as i32)?,
+                BitDepth::Float32 => self.writer.write_sample(s)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_wave(&mut self, wave: &Wave) -> anyhow::Result<()> {
+        for i in 0..wave.len() {
+            let left = wave.at(0, i);
+            let right = wave.at(wave.channels() - 1, i);
+            self.write_frame(left, right)?;
+        }
+        Ok(())
+    }
+
+    pub fn finalize(self) -> anyhow::Result<()> {
+        self.writer.finalize()?;
+        Ok(())
+    }
+}
+
+
+/// Renders a track's arrangement, sequence by sequence in `play_order`, to a stereo wave. The wave
+/// runs on past the end of the arrangement for as long as the slowest release in the track.
+pub fn render_track(track: &Track, sample_rate: f64) -> anyhow::Result<Wave> {
+    let mut rng = Rnd::from_u64(0);
+    let mut seq = Sequencer::new(false, 1);
+    seq.set_sample_rate(sample_rate);
+
+    let mut patches = PatchCache::new(track.bpm());
+    for note in track.notes()? {
+        let patch = track.patch(&note.patch)
+            .ok_or_else(|| anyhow!("unknown patch \"{}\"", note.patch))?;
+        let pnet = patches.get(&note.patch, patch)?;
+        let mut unit = voice::timed_note(pnet, note.freq, note.duration);
+        unit.ping(false, AttoHash::new(rng.u64()));
+        let end = note.start + note.duration + patch.release_time() as f64;
+        seq.push(note.start, end, Fade::Power, 0.0, 0.0, unit);
+    }
+
+    // room for notes held to the end of the track to ring out
+    let tail = track.patch_names()
+        .filter_map(|name| track.patch(name))
+        .map(|patch| patch.release_time())
+        .fold(0.0, f32::max);
+
+    let mut net = Net::wrap(Box::new(seq));
+    net.chain(Box::new(pan(0.0)));
+    Ok(Wave::render(sample_rate, track.length() + tail as f64, &mut net))
+}
+
+/// Renders `track` and writes it to a WAV file at `path`.
+pub fn render_to_file(track: &Track, path: &str, sample_rate: f64, depth: BitDepth) -> anyhow::Result<()> {
+    let wave = render_track(track, sample_rate)?;
+    let mut sink = WavSink::create(path, sample_rate, depth)?;
+    sink.write_wave(&wave)?;
+    sink.finalize()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    const RATE: f64 = 8000.0;
+
+    fn tiny_track() -> Track {
+        serde_yaml::from_str("
+            bpm: 120
+            patches:
+              lead:
+                nodes:
+                  osc: {op: Saw}
+                  env: {op: ADSR, attack: 0.01, decay: 0.1, sustain: 0.5, release: 0.2}
+                  mux: {op: Mux}
+                edges: [[freq, osc], [osc, 'mux:0'], [ctl, env], [env, 'mux:1'], [mux, out]]
+            sequences:
+              a:
+                layers:
+                  l1: {divisions: 2, patch: lead, notes: [0, 440]}
+            play_order: [[a, 2.0]]
+        ").unwrap()
+    }
+
+    #[test]
+    fn renders_a_track_to_wav() {
+        let dir = TestDir::new("render");
+        let path = dir.file("out.wav");
+        let track = tiny_track();
+        render_to_file(&track, &path, RATE, BitDepth::default()).unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let spec = reader.spec();
+        assert_eq!((spec.channels, spec.bits_per_sample, spec.sample_rate), (2, 16, RATE as u32));
+        // the release of the 0.2 s envelope follows the arrangement
+        let length = ((track.length() + 0.2) * RATE).round() as usize;
+        assert_eq!(reader.duration() as usize, length);
+
+        let samples: Vec<_> = reader.samples::<i16>().map(|s| s.unwrap().unsigned_abs()).collect();
+        let peak = |from: f64, to: f64| *samples[(from * RATE) as usize * 2..(to * RATE) as usize * 2].iter().max().unwrap();
+        assert!(peak(0.0, 1.0) == 0, "the rest should be silent");
+        assert!(peak(1.0, 2.0) > i16::MAX as u16 / 10, "the note should sound");
+        assert!(peak(2.0, 2.05) > i16::MAX as u16 / 20, "the release should ring past the end");
+        assert!(peak(2.18, 2.2) < peak(2.0, 2.02) / 4, "the release should die away");
+    }
+}
@@ -0,0 +1,41 @@
+use std::fs;
+use std::path::PathBuf;
+
+
+/// The commands in the script at `path` with their line numbers, leaving out blank lines and
+/// comments starting with `#`.
+pub fn read_lines(path: &str) -> anyhow::Result<Vec<(usize, String)>> {
+    let text = fs::read_to_string(path)?;
+    let lines = text.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty() && !l.trim_start().starts_with('#'))
+        .map(|(i, l)| (i + 1, l.to_string()))
+        .collect();
+    Ok(lines)
+}
+
+/// The script run when doris starts interactively: `$XDG_CONFIG_HOME/doris/dorisrc`, or
+/// `~/.config/doris/dorisrc`.
+pub fn rc_path() -> Option<PathBuf> {
+    let config = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("doris/dorisrc"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn skips_blanks_and_comments() {
+        let dir = TestDir::new("script");
+        let path = dir.file("script");
+        fs::write(&path, "# set up\nload track a.yaml\n\n   # indented comment\n  play track\n").unwrap();
+        let lines = read_lines(&path).unwrap();
+        assert_eq!(lines, [(2, "load track a.yaml".to_string()), (5, "  play track".to_string())]);
+    }
+}
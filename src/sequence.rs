@@ -1,21 +1,240 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
+use anyhow::bail;
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize)]
+use crate::yaml;
+
+/// Beats in one pass of a sequence layer's pattern.
+pub const BEATS_PER_BAR: f32 = 4.0;
+
+/// Length of one bar in seconds at `bpm`.
+pub fn bar_length(bpm: f32) -> f64 {
+    (BEATS_PER_BAR * 60.0 / bpm) as f64
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct SequenceLayer {
     divisions: usize,
     patch: String,
     notes: Vec<f32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    muted: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    soloed: bool,
+}
+
+impl SequenceLayer {
+    /// A layer of `divisions` rests played on `patch`.
+    pub fn new(divisions: usize, patch: &str) -> Self {
+        Self { divisions, patch: patch.to_string(), notes: vec![0.0; divisions], muted: false, soloed: false }
+    }
+
+    pub fn divisions(&self) -> usize {
+        self.divisions
+    }
+
+    /// Splits the bar into `divisions` steps, keeping the notes of the steps that remain.
+    pub fn set_divisions(&mut self, divisions: usize) -> anyhow::Result<()> {
+        if divisions == 0 {
+            bail!("a layer needs at least one division");
+        }
+        self.divisions = divisions;
+        self.notes.resize(divisions, 0.0);
+        Ok(())
+    }
+
+    pub fn patch(&self) -> &str {
+        &self.patch
+    }
+
+    pub fn set_patch(&mut self, patch: &str) {
+        self.patch = patch.to_string();
+    }
+
+    /// Frequency at step `i`; zero is a rest.
+    pub fn note(&self, i: usize) -> f32 {
+        self.notes.get(i).cloned().unwrap_or(0.0)
+    }
+
+    pub fn set_note(&mut self, i: usize, freq: f32) -> anyhow::Result<()> {
+        if i >= self.divisions {
+            bail!("step {i} is past the layer's {} divisions", self.divisions);
+        }
+        if !(freq >= 0.0 && freq.is_finite()) {
+            bail!("invalid frequency {freq}");
+        }
+        self.notes.resize(self.notes.len().max(self.divisions), 0.0);
+        self.notes[i] = freq;
+        Ok(())
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    pub fn is_soloed(&self) -> bool {
+        self.soloed
+    }
+
+    pub fn set_soloed(&mut self, soloed: bool) {
+        self.soloed = soloed;
+    }
+}
+
+/// A single note produced by a sequence layer, timed in seconds from the start of the sequence.
+pub struct SequenceNote {
+    pub patch: String,
+    pub freq: f32,
+    pub start: f64,
+    pub duration: f64,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Sequence {
-    layers: HashMap<String, SequenceLayer>
+    layers: BTreeMap<String, SequenceLayer>
 }
 
 impl Sequence {
     pub fn new() -> Self {
-        Self { layers: HashMap::new() }
+        Self { layers: BTreeMap::new() }
+    }
+
+    pub fn from_file(p: &str) -> anyhow::Result<Self> {
+        yaml::load(p)
+    }
+
+    pub fn to_file(&self, p: &str) -> anyhow::Result<()> {
+        yaml::save(self, p)
+    }
+
+    pub fn layers(&self) -> &BTreeMap<String, SequenceLayer> {
+        &self.layers
+    }
+
+    pub fn layer_mut(&mut self, name: &str) -> Option<&mut SequenceLayer> {
+        self.layers.get_mut(name)
+    }
+
+    pub fn add_layer(&mut self, name: &str, layer: SequenceLayer) -> anyhow::Result<()> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            bail!("invalid layer name \"{name}\"");
+        }
+        if self.layers.contains_key(name) {
+            bail!("there is already a layer named \"{name}\"");
+        }
+        self.layers.insert(name.to_string(), layer);
+        Ok(())
+    }
+
+    pub fn remove_layer(&mut self, name: &str) -> Option<SequenceLayer> {
+        self.layers.remove(name)
+    }
+
+    /// Whether `layer` is heard: not muted, and soloed if any layer is.
+    pub fn is_audible(&self, layer: &SequenceLayer) -> bool {
+        let any_soloed = self.layers.values().any(|l| l.soloed);
+        !layer.muted && (layer.soloed || !any_soloed)
+    }
+
+    /// Lays out the notes of every layer over `length` seconds at `bpm`.
+    ///
+    /// Each layer's pattern spans one bar, split into `divisions` equal steps; the pattern repeats
+    /// until `length` is filled. A step holds a frequency in Hz; zero (or a missing entry) is a rest.
+    /// Notes running past `length` are cut short. Muted layers, and unsoloed ones while any is
+    /// soloed, are left out.
+    pub fn notes(&self, bpm: f32, length: f64) -> Vec<SequenceNote> {
+        let bar = bar_length(bpm);
+        let mut rv = Vec::new();
+        for layer in self.layers.values() {
+            if layer.divisions == 0 || !self.is_audible(layer) {
+                continue;
+            }
+            let step = bar / layer.divisions as f64;
+            let mut i = 0;
+            loop {
+                let start = step * i as f64;
+                if start >= length {
+                    break;
+                }
+                let freq = layer.notes.get(i % layer.divisions).cloned().unwrap_or(0.0);
+                if freq > 0.0 {
+                    rv.push(SequenceNote {
+                        patch: layer.patch.clone(),
+                        freq,
+                        start,
+                        duration: step.min(length - start),
+                    });
+                }
+                i += 1;
+            }
+        }
+        rv.sort_by(|a, b| a.start.total_cmp(&b.start));
+        rv
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn round_trips_through_yaml() {
+        let sequence: Sequence = serde_yaml::from_str("
+            layers:
+              lead: {divisions: 4, patch: saw, notes: [220, 0, 330, 440]}
+              bass: {divisions: 2, patch: sine, notes: [110, 55]}
+              drums: {divisions: 8, patch: kick, notes: [1, 0, 0, 0, 1, 0, 1, 0]}
+        ").unwrap();
+
+        let dir = TestDir::new("sequence-round-trip");
+        let path = &dir.file("sequence.yaml");
+        sequence.to_file(path).unwrap();
+        let saved = std::fs::read_to_string(path).unwrap();
+        let loaded = Sequence::from_file(path).unwrap();
+        assert_eq!(saved, serde_yaml::to_string(&loaded).unwrap());
+        // layers are written in name order, whatever order they were read in
+        let order: Vec<_> = ["bass", "drums", "lead"].iter().map(|l| saved.find(l).unwrap()).collect();
+        assert!(order.is_sorted());
+    }
+
+    #[test]
+    fn mutes_and_solos_layers() {
+        let mut sequence = Sequence::new();
+        let mut lead = SequenceLayer::new(4, "saw");
+        lead.set_note(0, 220.0).unwrap();
+        lead.set_note(2, 330.0).unwrap();
+        assert!(lead.set_note(4, 440.0).is_err());
+        let mut bass = SequenceLayer::new(1, "sine");
+        bass.set_note(0, 55.0).unwrap();
+        sequence.add_layer("lead", lead).unwrap();
+        sequence.add_layer("bass", bass).unwrap();
+        assert!(sequence.add_layer("bass", SequenceLayer::new(1, "sine")).is_err());
+
+        let freqs = |s: &Sequence| -> Vec<f32> { s.notes(120.0, 2.0).iter().map(|n| n.freq).collect() };
+        assert_eq!(freqs(&sequence), [55.0, 220.0, 330.0]);
+        sequence.layer_mut("bass").unwrap().set_muted(true);
+        assert_eq!(freqs(&sequence), [220.0, 330.0]);
+        sequence.layer_mut("bass").unwrap().set_soloed(true);
+        assert!(freqs(&sequence).is_empty());
+        sequence.layer_mut("bass").unwrap().set_muted(false);
+        assert_eq!(freqs(&sequence), [55.0]);
+
+        // shrinking drops the steps past the end; growing adds rests
+        let lead = sequence.layer_mut("lead").unwrap();
+        lead.set_divisions(2).unwrap();
+        lead.set_divisions(3).unwrap();
+        assert_eq!((0..3).map(|i| lead.note(i)).collect::<Vec<_>>(), [220.0, 0.0, 0.0]);
+        assert!(lead.set_divisions(0).is_err());
+
+        // only set flags are written
+        let yaml = serde_yaml::to_string(&sequence).unwrap();
+        assert!(yaml.contains("soloed: true") && !yaml.contains("muted"));
     }
 }
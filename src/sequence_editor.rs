@@ -0,0 +1,551 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind};
+use ratatui::widgets::{Block, Borders, Paragraph, Widget};
+use ratatui::prelude::*;
+
+use crate::command_line;
+use crate::event_handler::EventHandler;
+use crate::frame_renderable::FrameRenderable;
+use crate::keyboard::Note;
+use crate::sequence::{self, Sequence, SequenceLayer};
+
+
+/// A step's note as typed: a name like `A4` if it is one, otherwise the frequency in Hz.
+fn note_text(freq: f32) -> String {
+    for octave in -1..10 {
+        for i in 0..12 {
+            let note = Note::from_index(i);
+            // the keyboard's frequencies are rounded to a tenth of a hertz in octave 3, so allow
+            // for that but no more: 330 Hz stays 330 rather than becoming E4
+            if (note.to_freq_octave(octave) / freq - 1.0).abs() < 0.0005 {
+                return format!("{}{octave}", note.name());
+            }
+        }
+    }
+    format!("{freq}")
+}
+
+/// Reads a step from a note name or a frequency in Hz; blank, `0` or `-` is a rest.
+fn parse_step(text: &str) -> anyhow::Result<f32> {
+    let text = text.trim();
+    if text.is_empty() || text == "-" {
+        return Ok(0.0);
+    }
+    if let Some((note, octave)) = command_line::parse_note(text) {
+        return Ok(note.to_freq_octave(octave));
+    }
+    match text.parse::<f32>() {
+        Ok(freq) if freq >= 0.0 && freq.is_finite() => Ok(freq),
+        _ => anyhow::bail!("expected a note like A4 or a frequency in Hz, not \"{text}\""),
+    }
+}
+
+
+/// What the text typed into the editor's prompt is for.
+enum PromptKind {
+    Note,
+    Patch,
+    AddLayer,
+}
+
+struct Prompt {
+    kind: PromptKind,
+    text: String,
+    /// What Tab is completing patch names from, while cycling through them.
+    stem: Option<String>,
+}
+
+impl Prompt {
+    fn new(kind: PromptKind, text: String) -> Self {
+        Self { kind, text, stem: None }
+    }
+
+    fn label(&self) -> &str {
+        match self.kind {
+            PromptKind::Note => "note, e.g. A4 or 440; blank for a rest",
+            PromptKind::Patch => "patch",
+            PromptKind::AddLayer => "add $name [$divisions]",
+        }
+    }
+
+    /// Completes the text to the next of `names` starting with what was typed.
+    fn complete(&mut self, names: &[String]) {
+        let stem = self.stem.get_or_insert_with(|| self.text.clone());
+        let matches: Vec<_> = names.iter().filter(|n| n.starts_with(stem.as_str())).collect();
+        let next = matches.iter()
+            .position(|n| **n == self.text)
+            .map_or(0, |i| (i + 1) % matches.len());
+        if let Some(name) = matches.get(next) {
+            self.text = name.to_string();
+        }
+    }
+}
+
+
+/// Step-sequencer grid for a sequence: a row per layer, split into the layer's divisions of the
+/// bar, with the transport's playhead over it.
+pub struct SequenceEditor {
+    sequence: Sequence,
+    /// Name of the sequence in the track.
+    name: Option<String>,
+    /// The track's patches, for layers to play on.
+    patches: Vec<String>,
+    row: usize,
+    step: usize,
+    /// What Space fills a rest with: the last note entered.
+    last_note: f32,
+    /// How far through the bar the transport is, while it plays this sequence.
+    playhead: Option<f64>,
+    prompt: Option<Prompt>,
+    /// Why the last edit failed.
+    message: Option<String>,
+    changed: bool,
+    finished: bool,
+}
+
+impl SequenceEditor {
+    const LABEL_WIDTH: u16 = 28;
+    const DEFAULT_DIVISIONS: usize = 8;
+
+    pub fn new(sequence: Sequence, name: Option<String>) -> Self {
+        Self {
+            sequence,
+            name,
+            patches: Vec::new(),
+            row: 0,
+            step: 0,
+            last_note: 440.0,
+            playhead: None,
+            prompt: None,
+            message: None,
+            changed: false,
+            finished: false,
+        }
+    }
+
+    /// Starts editing `sequence`, dropping anything half done on the last one.
+    pub fn set_sequence(&mut self, sequence: Sequence, name: Option<String>) {
+        let patches = std::mem::take(&mut self.patches);
+        *self = Self::new(sequence, name);
+        self.patches = patches;
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn set_patches(&mut self, patches: Vec<String>) {
+        self.patches = patches;
+    }
+
+    /// Where the transport is through the bar, from 0 to 1, or `None` if it isn't playing this sequence.
+    pub fn set_playhead(&mut self, playhead: Option<f64>) {
+        self.playhead = playhead;
+    }
+
+    /// The sequence as edited, if it has changed since last asked.
+    pub fn take_changed(&mut self) -> Option<Sequence> {
+        if self.changed {
+            self.changed = false;
+            Some(self.sequence.clone())
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn set_unfinished(&mut self) {
+        self.finished = false;
+    }
+
+    fn selected(&self) -> Option<(&String, &SequenceLayer)> {
+        self.sequence.layers().iter().nth(self.row)
+    }
+
+    /// Keeps the cursor on a step of a layer.
+    fn clamp_cursor(&mut self) {
+        self.row = self.row.min(self.sequence.layers().len().saturating_sub(1));
+        let divisions = self.selected().map_or(1, |(_, l)| l.divisions());
+        self.step = self.step.min(divisions.saturating_sub(1));
+    }
+
+    /// Moves the cursor to another layer, onto the step nearest the same point in the bar.
+    fn move_row(&mut self, dy: isize) {
+        let from = self.selected().map_or(1, |(_, l)| l.divisions());
+        self.row = (self.row as isize + dy).max(0) as usize;
+        self.clamp_cursor();
+        let to = self.selected().map_or(1, |(_, l)| l.divisions());
+        self.step = (self.step * to / from.max(1)).min(to.saturating_sub(1));
+    }
+
+    /// Applies `edit` to the selected layer, keeping the result if it succeeds.
+    fn edit_layer(&mut self, edit: impl FnOnce(&mut SequenceLayer) -> anyhow::Result<()>) {
+        let Some(name) = self.selected().map(|(n, _)| n.clone())
+        else {
+            self.message = Some("no layers yet; a adds one".into());
+            return;
+        };
+        let mut sequence = self.sequence.clone();
+        let Some(layer) = sequence.layer_mut(&name)
+        else {
+            return;
+        };
+        match edit(layer) {
+            Ok(()) => {
+                self.sequence = sequence;
+                self.changed = true;
+                self.message = None;
+            },
+            Err(e) => self.message = Some(e.to_string()),
+        }
+        self.clamp_cursor();
+    }
+
+    fn open_prompt(&mut self, kind: PromptKind) {
+        let layer = self.selected().map(|(_, l)| l);
+        let text = match kind {
+            PromptKind::Note => layer.map(|l| l.note(self.step)).filter(|f| *f > 0.0).map(note_text),
+            PromptKind::Patch => layer.map(|l| l.patch().to_string()),
+            PromptKind::AddLayer => None,
+        };
+        if layer.is_none() && !matches!(kind, PromptKind::AddLayer) {
+            self.message = Some("no layers yet; a adds one".into());
+            return;
+        }
+        self.prompt = Some(Prompt::new(kind, text.unwrap_or_default()));
+    }
+
+    fn run_prompt(&mut self, prompt: Prompt) {
+        match prompt.kind {
+            PromptKind::Note => match parse_step(&prompt.text) {
+                Ok(freq) => {
+                    let step = self.step;
+                    self.edit_layer(|l| l.set_note(step, freq));
+                    if freq > 0.0 {
+                        self.last_note = freq;
+                    }
+                },
+                Err(e) => self.message = Some(e.to_string()),
+            },
+            PromptKind::Patch => {
+                let patch = prompt.text.trim().to_string();
+                if !self.patches.contains(&patch) {
+                    self.message = Some(format!("no patch named \"{patch}\" in the track; Tab completes"));
+                    return;
+                }
+                self.edit_layer(|l| { l.set_patch(&patch); Ok(()) });
+            },
+            PromptKind::AddLayer => {
+                let words: Vec<&str> = prompt.text.split_whitespace().collect();
+                let (name, divisions) = match words.as_slice() {
+                    [name] => (*name, None),
+                    [name, divisions] => (*name, divisions.parse::<usize>().ok().filter(|d| *d > 0)),
+                    _ => {
+                        self.message = Some("expected a layer name and, optionally, divisions".into());
+                        return;
+                    },
+                };
+                if words.len() == 2 && divisions.is_none() {
+                    self.message = Some(format!("invalid divisions \"{}\"", words[1]));
+                    return;
+                }
+                // follow the layer selected, or the track's first patch
+                let (patch, default_divisions) = match self.selected() {
+                    Some((_, l)) => (Some(l.patch().to_string()), l.divisions()),
+                    None => (self.patches.first().cloned(), Self::DEFAULT_DIVISIONS),
+                };
+                let Some(patch) = patch
+                else {
+                    self.message = Some("the track has no patches for a layer to play".into());
+                    return;
+                };
+                let layer = SequenceLayer::new(divisions.unwrap_or(default_divisions), &patch);
+                let mut sequence = self.sequence.clone();
+                match sequence.add_layer(name, layer) {
+                    Ok(()) => {
+                        self.row = sequence.layers().keys().position(|n| n == name).unwrap_or(0);
+                        self.sequence = sequence;
+                        self.changed = true;
+                        self.message = None;
+                        self.clamp_cursor();
+                    },
+                    Err(e) => self.message = Some(e.to_string()),
+                }
+            },
+        }
+    }
+
+    fn handle_prompt_key(&mut self, kev: KeyEvent) {
+        let Some(prompt) = self.prompt.as_mut()
+        else {
+            return;
+        };
+        match kev.code {
+            KeyCode::Enter => {
+                if let Some(prompt) = self.prompt.take() {
+                    self.run_prompt(prompt);
+                }
+            },
+            KeyCode::Esc => self.prompt = None,
+            KeyCode::Tab if matches!(prompt.kind, PromptKind::Patch) => prompt.complete(&self.patches),
+            KeyCode::Backspace => {
+                prompt.text.pop();
+                prompt.stem = None;
+            },
+            KeyCode::Char(c) => {
+                prompt.text.push(c);
+                prompt.stem = None;
+            },
+            _ => (),
+        }
+    }
+
+    /// A layer's row: its name, patch and mute/solo flags, then a cell per step.
+    fn layer_line(&self, row: usize, name: &str, layer: &SequenceLayer, width: u16) -> Line<'static> {
+        let audible = self.sequence.is_audible(layer);
+        let selected = row == self.row;
+        let mut spans = Vec::new();
+
+        let label_width = Self::LABEL_WIDTH as usize - 6;
+        let label = format!("{name} {}", layer.patch());
+        let label = format!("{:<label_width$.label_width$}", label);
+        spans.push(if selected { Span::raw(label).bold() } else { Span::raw(label) });
+        spans.push(if layer.is_muted() { Span::raw(" M").red().bold() } else { Span::raw(" M").dark_gray() });
+        spans.push(if layer.is_soloed() { Span::raw(" S").yellow().bold() } else { Span::raw(" S").dark_gray() });
+        spans.push(Span::raw("  "));
+
+        let divisions = layer.divisions().max(1);
+        let playing = self.playhead.map(|p| (p * divisions as f64) as usize);
+        for i in 0..divisions {
+            let x0 = i * width as usize / divisions;
+            let x1 = (i + 1) * width as usize / divisions;
+            let cell = x1 - x0;
+            if cell == 0 {
+                continue;
+            }
+            let freq = layer.note(i);
+            let text = if freq > 0.0 { note_text(freq) } else { "·".to_string() };
+            // leave a gap between steps where there's room for one
+            let inner = if cell > 1 { cell - 1 } else { cell };
+            let text = format!("{:<inner$.inner$}{}", text, " ".repeat(cell - inner));
+
+            let mut style = if freq > 0.0 { Style::new() } else { Style::new().dark_gray() };
+            if Some(i) == playing {
+                style = style.on_dark_gray().white();
+            }
+            if selected && i == self.step {
+                style = style.reversed();
+            }
+            if !audible {
+                style = style.dim();
+            }
+            spans.push(Span::styled(text, style));
+        }
+        Line::from(spans)
+    }
+
+    /// Beat marks across the bar, with the playhead.
+    fn ruler(&self, width: u16) -> Line<'static> {
+        let width = width as usize;
+        let beats = sequence::BEATS_PER_BAR as usize;
+        let mut ruler: Vec<char> = (0..width)
+            .map(|x| if x * beats % width < beats { '|' } else { ' ' })
+            .collect();
+        if let Some(p) = self.playhead {
+            let x = ((p * width as f64) as usize).min(width.saturating_sub(1));
+            if let Some(c) = ruler.get_mut(x) {
+                *c = '▼';
+            }
+        }
+        Line::from(vec![
+            Span::raw(" ".repeat(Self::LABEL_WIDTH as usize)),
+            Span::raw(ruler.into_iter().collect::<String>()).dark_gray(),
+        ])
+    }
+}
+
+
+impl EventHandler for SequenceEditor {
+    fn handle_key(&mut self, kev: KeyEvent) -> anyhow::Result<bool> {
+        if kev.kind != KeyEventKind::Press {
+            return Ok(false);
+        }
+        if self.prompt.is_some() {
+            self.handle_prompt_key(kev);
+            return Ok(false);
+        }
+
+        // a layer loaded with no divisions still has a step for the cursor
+        let divisions = self.selected().map_or(1, |(_, l)| l.divisions()).max(1);
+        let step = self.step;
+        match kev.code {
+            KeyCode::Esc => self.finished = true,
+            KeyCode::Up => self.move_row(-1),
+            KeyCode::Down => self.move_row(1),
+            KeyCode::Left => self.step = (step + divisions - 1) % divisions,
+            KeyCode::Right => self.step = (step + 1) % divisions,
+            KeyCode::Enter => self.open_prompt(PromptKind::Note),
+            KeyCode::Char(' ') => {
+                let note = self.last_note;
+                self.edit_layer(|l| l.set_note(step, if l.note(step) > 0.0 { 0.0 } else { note }));
+            },
+            KeyCode::Delete | KeyCode::Backspace => self.edit_layer(|l| l.set_note(step, 0.0)),
+            KeyCode::Char('m') => self.edit_layer(|l| { l.set_muted(!l.is_muted()); Ok(()) }),
+            KeyCode::Char('s') => self.edit_layer(|l| { l.set_soloed(!l.is_soloed()); Ok(()) }),
+            KeyCode::Char('+') | KeyCode::Char('=') => self.edit_layer(|l| l.set_divisions(l.divisions() + 1)),
+            KeyCode::Char('-') => self.edit_layer(|l| l.set_divisions(l.divisions().saturating_sub(1))),
+            KeyCode::Char('p') => self.open_prompt(PromptKind::Patch),
+            KeyCode::Char('a') => self.open_prompt(PromptKind::AddLayer),
+            KeyCode::Char('d') => {
+                if let Some(name) = self.selected().map(|(n, _)| n.clone()) {
+                    self.sequence.remove_layer(&name);
+                    self.changed = true;
+                    self.clamp_cursor();
+                }
+            },
+            _ => (),
+        }
+        Ok(false)
+    }
+}
+
+
+impl FrameRenderable for SequenceEditor {
+    fn draw_into(&self, frame: &mut Frame, area: Rect) {
+        let title = match &self.name {
+            Some(name) => format!(" sequence \"{name}\" "),
+            None => " sequence ".to_string(),
+        };
+        let block = Block::new()
+            .borders(Borders::ALL)
+            .title(title)
+            .title_bottom(
+                Line::from("arrows move; enter note; space toggle; m mute; s solo; p patch; +/- divisions; a add; d delete; esc back.")
+                .centered()
+            );
+        let inner = block.inner(area);
+        block.render(area, frame.buffer_mut());
+
+        let [grid, info, prompt_area] = Layout::new(Direction::Vertical, vec![
+            Constraint::Min(0),
+            Constraint::Length(2),
+            Constraint::Length(2),
+        ]).areas(inner);
+
+        let width = grid.width.saturating_sub(Self::LABEL_WIDTH);
+        let mut lines = vec![self.ruler(width)];
+        for (row, (name, layer)) in self.sequence.layers().iter().enumerate() {
+            lines.push(self.layer_line(row, name, layer, width));
+        }
+        if self.sequence.layers().is_empty() {
+            lines.push(Line::from("No layers; a adds one.").dark_gray());
+        }
+        Paragraph::new(lines).render(grid, frame.buffer_mut());
+
+        let mut info_lines = Vec::new();
+        if let Some((name, layer)) = self.selected() {
+            let freq = layer.note(self.step);
+            let step = if freq > 0.0 { format!("{} ({freq} Hz)", note_text(freq)) } else { "rest".to_string() };
+            info_lines.push(Line::from(format!(
+                "{name}: {} divisions on \"{}\"; step {}: {step}", layer.divisions(), layer.patch(), self.step + 1
+            )));
+        }
+        if let Some(message) = &self.message {
+            info_lines.push(Line::from(message.as_str()).red());
+        }
+        Paragraph::new(info_lines).render(info, frame.buffer_mut());
+
+        if let Some(prompt) = &self.prompt {
+            Paragraph::new(vec![
+                Line::from(prompt.label()).dark_gray(),
+                Line::from(vec![Span::styled("> ", Style::new().dim()), Span::raw(&prompt.text)]),
+            ]).render(prompt_area, frame.buffer_mut());
+            let cx = prompt_area.x + 2 + prompt.text.chars().count() as u16;
+            frame.set_cursor_position((cx.min(prompt_area.right().saturating_sub(1)), prompt_area.y + 1));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::KeyModifiers;
+
+    use super::*;
+
+    fn type_keys(editor: &mut SequenceEditor, keys: &str) {
+        for c in keys.chars() {
+            let code = match c {
+                '\n' => KeyCode::Enter,
+                '\t' => KeyCode::Tab,
+                '\x08' => KeyCode::Backspace,
+                '>' => KeyCode::Right,
+                '<' => KeyCode::Left,
+                'v' => KeyCode::Down,
+                c => KeyCode::Char(c),
+            };
+            editor.handle_key(KeyEvent::new(code, KeyModifiers::NONE)).unwrap();
+        }
+    }
+
+    #[test]
+    fn names_notes() {
+        assert_eq!(note_text(440.0), "A4");
+        assert_eq!(note_text(130.8), "C3");
+        assert_eq!(note_text(1.0), "1");
+        assert_eq!(note_text(330.0), "330");
+        assert_eq!(parse_step("C#3").unwrap(), Note::CSharp.to_freq());
+        assert_eq!(parse_step("55").unwrap(), 55.0);
+        assert_eq!(parse_step(" - ").unwrap(), 0.0);
+        assert!(parse_step("loud").is_err());
+    }
+
+    #[test]
+    fn edits_steps_and_layers() {
+        let mut editor = SequenceEditor::new(Sequence::new(), Some("a".into()));
+        type_keys(&mut editor, "alead\n");
+        assert!(editor.message.is_some());
+        editor.set_patches(vec!["saw".into(), "sine".into()]);
+        type_keys(&mut editor, "alead 4\n");
+
+        // A4, a rest, C3 by name, and Space repeating the last note entered
+        type_keys(&mut editor, "\nA4\n>>\nC3\n> ");
+        let sequence = editor.take_changed().unwrap();
+        let lead = &sequence.layers()["lead"];
+        assert_eq!((0..4).map(|i| lead.note(i)).collect::<Vec<_>>(), [440.0, 0.0, 130.8, 130.8]);
+
+        type_keys(&mut editor, "abass 2\n");
+        assert_eq!(editor.row, 0);
+        type_keys(&mut editor, "p\x08\x08\x08s\t\t\n");
+        assert_eq!(editor.sequence.layers()["bass"].patch(), "sine");
+        type_keys(&mut editor, "p\x08\x08\x08organ\n");
+        assert!(editor.message.is_some());
+
+        type_keys(&mut editor, "vms-");
+        let lead = &editor.sequence.layers()["lead"];
+        assert!(lead.is_muted() && lead.is_soloed());
+        assert_eq!(lead.divisions(), 3);
+        assert_eq!(editor.step, 2);
+        type_keys(&mut editor, "d");
+        assert_eq!(editor.sequence.layers().keys().collect::<Vec<_>>(), ["bass"]);
+    }
+
+    #[test]
+    fn survives_a_layer_without_divisions() {
+        let sequence: Sequence = serde_yaml::from_str("
+            layers:
+              empty: {divisions: 0, patch: saw, notes: []}
+        ").unwrap();
+        let mut editor = SequenceEditor::new(sequence, Some("a".into()));
+        type_keys(&mut editor, "<>- ");
+        assert_eq!(editor.step, 0);
+        assert!(editor.message.is_some());
+        assert_eq!(editor.sequence.layers()["empty"].divisions(), 0);
+
+        type_keys(&mut editor, "+");
+        assert_eq!(editor.sequence.layers()["empty"].divisions(), 1);
+    }
+}
@@ -0,0 +1,31 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+
+/// Scratch directory for one test, named after the test and the process so that tests running side
+/// by side never share files. It is removed, with everything in it, when dropped.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new(test: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("doris-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Path of `name` in the directory, as the load and save functions take it.
+    pub fn file(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
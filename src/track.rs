@@ -1,29 +1,106 @@
-use std::collections::HashMap;
-use std::fs::OpenOptions;
+use std::collections::BTreeMap;
 
 use ratatui::Frame;
 use serde::{Serialize, Deserialize};
 
+use anyhow::{anyhow, bail};
+
 use crate::patch::Patch;
-use crate::sequence::Sequence;
+use crate::sequence::{Sequence, SequenceNote};
+use crate::yaml;
 
 #[derive(Serialize, Deserialize)]
 pub struct Track {
     bpm: f32,
-    patches: HashMap<String, Patch>,
-    sequences: HashMap<String, Sequence>,
+    patches: BTreeMap<String, Patch>,
+    sequences: BTreeMap<String, Sequence>,
     play_order: Vec<(String, f32)>, // sequence name, length (seconds)
 }
 
+/// An entry of the play order, placed on the track's timeline.
+pub struct Section {
+    pub sequence: String,
+    pub start: f64,
+    pub length: f64,
+}
+
 
 impl Track {
     pub fn new() -> Self {
-        Track { bpm: 140.0, patches: HashMap::new(), sequences: HashMap::new(), play_order: Vec::new() }
+        Track { bpm: 140.0, patches: BTreeMap::new(), sequences: BTreeMap::new(), play_order: Vec::new() }
     }
 
     pub fn from_file(p: &str) -> anyhow::Result<Self> {
-        let f = OpenOptions::new().read(true).open(p)?;
-        let rv = serde_yaml::from_reader(f)?;
+        let track: Self = yaml::load(p)?;
+        if !(track.bpm.is_finite() && track.bpm > 0.0) {
+            bail!("bpm must be a positive number, not {}", track.bpm);
+        }
+        Ok(track)
+    }
+
+    pub fn to_file(&self, p: &str) -> anyhow::Result<()> {
+        yaml::save(self, p)
+    }
+
+    pub fn bpm(&self) -> f32 {
+        self.bpm
+    }
+
+    pub fn patch(&self, name: &str) -> Option<&Patch> {
+        self.patches.get(name)
+    }
+
+    pub fn sequence(&self, name: &str) -> Option<&Sequence> {
+        self.sequences.get(name)
+    }
+
+    pub fn patch_names(&self) -> impl Iterator<Item = &String> {
+        self.patches.keys()
+    }
+
+    pub fn sequence_names(&self) -> impl Iterator<Item = &String> {
+        self.sequences.keys()
+    }
+
+    /// Adds or replaces the patch called `name`, returning the one it replaced.
+    pub fn insert_patch(&mut self, name: &str, patch: Patch) -> Option<Patch> {
+        self.patches.insert(name.to_string(), patch)
+    }
+
+    /// Adds or replaces the sequence called `name`, returning the one it replaced.
+    pub fn insert_sequence(&mut self, name: &str, sequence: Sequence) -> Option<Sequence> {
+        self.sequences.insert(name.to_string(), sequence)
+    }
+
+    /// Total length of the arrangement in seconds.
+    pub fn length(&self) -> f64 {
+        self.play_order.iter().map(|(_, l)| *l as f64).sum()
+    }
+
+    pub fn sections(&self) -> Vec<Section> {
+        let mut start = 0.0;
+        self.play_order.iter().map(|(name, length)| {
+            let section = Section { sequence: name.clone(), start, length: *length as f64 };
+            start += *length as f64;
+            section
+        }).collect()
+    }
+
+    /// Every note of the arrangement, sequence after sequence in `play_order`, timed from the start
+    /// of the track.
+    pub fn notes(&self) -> anyhow::Result<Vec<SequenceNote>> {
+        let mut rv = Vec::new();
+        for section in self.sections() {
+            let sequence = self.sequence(&section.sequence)
+                .ok_or_else(|| anyhow!("play order refers to unknown sequence \"{}\"", section.sequence))?;
+            for mut note in sequence.notes(self.bpm, section.length) {
+                if self.patch(&note.patch).is_none() {
+                    return Err(anyhow!("sequence \"{}\" refers to unknown patch \"{}\"", section.sequence, note.patch));
+                }
+                note.start += section.start;
+                rv.push(note);
+            }
+        }
         Ok(rv)
     }
 
@@ -33,3 +110,58 @@ impl Track {
     pub fn draw_patch_list(&self, frame: &mut Frame) {
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn round_trips_through_yaml() {
+        let track: Track = serde_yaml::from_str("
+            bpm: 120
+            patches:
+              lead:
+                nodes:
+                  osc: {op: Saw}
+                  env: {op: ADSR, attack: 0.01, decay: 0.1, sustain: 0.5, release: 0.2}
+                  mux: {op: Mux}
+                edges: [[freq, osc], [osc, 'mux:0'], [ctl, env], [env, 'mux:1'], [mux, out]]
+              bass:
+                nodes:
+                  osc: {op: Sine}
+                edges: [[freq, osc], [osc, out]]
+                voices: {max_voices: 1, mode: {mode: Legato}}
+            sequences:
+              verse:
+                layers:
+                  l1: {divisions: 4, patch: lead, notes: [220, 0, 330, 440]}
+              chorus:
+                layers:
+                  l1: {divisions: 2, patch: bass, notes: [110, 55]}
+            play_order: [[verse, 2.0], [chorus, 1.0], [verse, 2.0]]
+        ").unwrap();
+
+        let dir = TestDir::new("track-round-trip");
+        let path = &dir.file("track.yaml");
+        track.to_file(path).unwrap();
+        let saved = std::fs::read_to_string(path).unwrap();
+        let loaded = Track::from_file(path).unwrap();
+        assert_eq!(saved, serde_yaml::to_string(&loaded).unwrap());
+        assert!(saved.find("bass:").unwrap() < saved.find("lead:").unwrap());
+        assert!(saved.find("chorus:").unwrap() < saved.find("verse:").unwrap());
+    }
+
+    #[test]
+    fn rejects_a_bad_bpm() {
+        let dir = TestDir::new("track-bpm");
+        let path = &dir.file("track.yaml");
+        for bpm in ["0", "-90", ".nan"] {
+            std::fs::write(path, format!("{{bpm: {bpm}, patches: {{}}, sequences: {{}}, play_order: []}}")).unwrap();
+            assert!(Track::from_file(path).is_err(), "bpm {bpm}");
+        }
+        std::fs::write(path, "{bpm: 90, patches: {}, sequences: {}, play_order: []}").unwrap();
+        assert_eq!(Track::from_file(path).unwrap().bpm(), 90.0);
+    }
+}
@@ -0,0 +1,434 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{anyhow, bail};
+use fundsp::funutd::Rnd;
+use fundsp::hacker::*;
+use ratatui::layout::Rect;
+use ratatui::text::Line;
+use ratatui::widgets::Widget;
+
+use crate::frame_renderable::FrameRenderable;
+use crate::patch_cache::PatchCache;
+use crate::sequence::SequenceNote;
+use crate::track::{Section, Track};
+use crate::voice;
+
+
+/// Pass-through node counting the samples the audio thread has processed, so the UI thread can tell
+/// where the sequencer's clock is.
+#[derive(Clone)]
+pub struct Clock {
+    samples: Arc<AtomicU64>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self { samples: Arc::new(AtomicU64::new(0)) }
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples.load(Ordering::Relaxed)
+    }
+}
+
+impl AudioNode for Clock {
+    const ID: u64 = 0xd0415;
+    type Inputs = U1;
+    type Outputs = U1;
+
+    fn tick(&mut self, input: &Frame<f32, Self::Inputs>) -> Frame<f32, Self::Outputs> {
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        *input
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TransportState {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+struct ScheduledNote {
+    event_id: EventId,
+    start: f64,
+    end: f64,
+}
+
+/// A stretch of the timeline played without jumping: from `from` to `to` (positions, in seconds),
+/// with `from` heard at sequencer time `time`.
+#[derive(Clone, Copy)]
+struct Pass {
+    from: f64,
+    to: f64,
+    time: f64,
+}
+
+/// Plays notes on the sequencer in time with the audio clock, scheduling them a little ahead so
+/// each starts on its exact sample.
+pub struct Transport {
+    clock: Clock,
+    sample_rate: f64,
+    state: TransportState,
+    notes: Vec<SequenceNote>,
+    sections: Vec<Section>,
+    length: f64,
+    looping: bool,
+    /// Part of the timeline to loop, in seconds; the whole of it if `None`.
+    loop_region: Option<(f64, f64)>,
+    /// Position while stopped or paused, in seconds.
+    position: f64,
+    /// Where and when playback last started.
+    started: Pass,
+    /// The pass being scheduled, and how far into it notes have been scheduled.
+    pass: Pass,
+    scheduled: f64,
+    events: Vec<ScheduledNote>,
+}
+
+impl Transport {
+    /// How far ahead of the audio clock notes are put on the sequencer.
+    const LOOKAHEAD: f64 = 0.1;
+    /// Fade for notes cut short by stopping or seeking.
+    const CUT_FADE: f64 = 0.005;
+
+    pub fn new(clock: Clock, sample_rate: f64) -> Self {
+        let pass = Pass { from: 0.0, to: 0.0, time: 0.0 };
+        Self {
+            clock,
+            sample_rate,
+            state: TransportState::Stopped,
+            notes: Vec::new(),
+            sections: Vec::new(),
+            length: 0.0,
+            looping: true,
+            loop_region: None,
+            position: 0.0,
+            started: pass,
+            pass,
+            scheduled: 0.0,
+            events: Vec::new(),
+        }
+    }
+
+    /// Replaces what the transport plays with `notes` over `length` seconds, stopping playback.
+    /// `sections` name the parts of the timeline for the now playing indicator.
+    pub fn load(&mut self, seq: &mut Sequencer, notes: Vec<SequenceNote>, sections: Vec<Section>, length: f64) -> anyhow::Result<()> {
+        if length <= 0.0 {
+            bail!("nothing to play: length is {length} seconds");
+        }
+        self.stop(seq);
+        self.notes = notes;
+        self.sections = sections;
+        self.length = length;
+        self.loop_region = None;
+        Ok(())
+    }
+
+    /// Swaps in new notes without stopping, e.g. after the sequence playing is edited; notes
+    /// already put on the sequencer still play.
+    pub fn replace_notes(&mut self, notes: Vec<SequenceNote>) {
+        self.notes = notes;
+    }
+
+    pub fn state(&self) -> TransportState {
+        self.state
+    }
+
+    pub fn set_looping(&mut self, seq: &mut Sequencer, looping: bool) {
+        self.restart(seq, |t| t.looping = looping);
+    }
+
+    /// Loops between `start` and `end` seconds rather than over the whole timeline.
+    pub fn set_loop_region(&mut self, seq: &mut Sequencer, start: f64, end: f64) -> anyhow::Result<()> {
+        if !(0.0 <= start && start < end && end <= self.length) {
+            bail!("invalid loop region {start}s to {end}s: length is {}s", self.length);
+        }
+        self.restart(seq, |t| {
+            t.loop_region = Some((start, end));
+            t.looping = true;
+        });
+        Ok(())
+    }
+
+    /// Current playback position in seconds.
+    pub fn position(&self) -> f64 {
+        match self.state {
+            TransportState::Playing => {
+                let pos = self.started.from + (self.now() - self.started.time).max(0.0);
+                match self.region() {
+                    // the first pass runs to the end of the region, or of the timeline if it started after
+                    // the region; the rest go round the region
+                    Some((start, end)) if pos >= self.started.to => {
+                        start + (pos - self.started.to) % (end - start)
+                    },
+                    _ => pos.min(self.length),
+                }
+            },
+            _ => self.position,
+        }
+    }
+
+    /// The section at the current position, and its index.
+    pub fn now_playing(&self) -> Option<(usize, &Section)> {
+        let pos = self.position();
+        self.sections.iter()
+            .enumerate()
+            .find(|(_, s)| s.start <= pos && pos < s.start + s.length)
+    }
+
+    pub fn play(&mut self) -> anyhow::Result<()> {
+        if self.length <= 0.0 {
+            bail!("nothing loaded to play");
+        }
+        if self.state == TransportState::Playing {
+            return Ok(());
+        }
+        let from = self.position;
+        let to = match self.region() {
+            Some((_, end)) if from < end => end,
+            _ => self.length,
+        };
+        self.started = Pass { from, to, time: self.now() + Self::LOOKAHEAD };
+        self.pass = self.started;
+        self.scheduled = from;
+        self.state = TransportState::Playing;
+        Ok(())
+    }
+
+    pub fn pause(&mut self, seq: &mut Sequencer) {
+        if self.state == TransportState::Playing {
+            self.position = self.position();
+            self.cancel(seq);
+            self.state = TransportState::Paused;
+        }
+    }
+
+    pub fn stop(&mut self, seq: &mut Sequencer) {
+        self.cancel(seq);
+        self.position = 0.0;
+        self.state = TransportState::Stopped;
+    }
+
+    pub fn seek(&mut self, seq: &mut Sequencer, position: f64) -> anyhow::Result<()> {
+        if !(0.0..self.length).contains(&position) {
+            bail!("cannot seek to {position}s: length is {}s", self.length);
+        }
+        self.position = position;
+        if self.state == TransportState::Playing {
+            self.cancel(seq);
+            self.state = TransportState::Paused;
+            self.play()?;
+        }
+        Ok(())
+    }
+
+    /// Schedules the notes due within the lookahead; call regularly while playing.
+    pub fn update(&mut self, seq: &mut Sequencer, track: &Track, cache: &mut PatchCache, rng: &mut Rnd) -> anyhow::Result<()> {
+        let now = self.now();
+        self.events.retain(|e| e.end > now);
+        if self.state != TransportState::Playing {
+            return Ok(());
+        }
+
+        let horizon = now + Self::LOOKAHEAD;
+        loop {
+            let pass = self.pass;
+            let until = pass.to.min(pass.from + (horizon - pass.time));
+            for note in self.notes.iter() {
+                if note.start < self.scheduled || note.start >= until {
+                    continue;
+                }
+                let patch = track.patch(&note.patch)
+                    .ok_or_else(|| anyhow!("unknown patch \"{}\"", note.patch))?;
+                let pnet = cache.get(&note.patch, patch)?;
+                let mut unit = voice::timed_note(pnet, note.freq, note.duration);
+                unit.ping(false, AttoHash::new(rng.u64()));
+                let start = pass.time + (note.start - pass.from);
+                let end = start + note.duration + patch.release_time() as f64;
+                let event_id = seq.push(start, end, Fade::Power, 0.0, 0.0, unit);
+                self.events.push(ScheduledNote { event_id, start, end });
+            }
+            self.scheduled = until;
+            if until < pass.to {
+                break;
+            }
+
+            // the pass is fully scheduled; carry on from the start of the loop, or finish
+            let ends = pass.time + (pass.to - pass.from);
+            match self.region() {
+                Some((start, end)) => {
+                    self.pass = Pass { from: start, to: end, time: ends };
+                    self.scheduled = start;
+                },
+                None => {
+                    if now >= ends {
+                        self.state = TransportState::Stopped;
+                        self.position = 0.0;
+                    }
+                    break;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies `change` to the loop settings, restarting playback from the current position; the
+    /// position is taken before the change, as it depends on them.
+    fn restart(&mut self, seq: &mut Sequencer, change: impl FnOnce(&mut Self)) {
+        let playing = self.state == TransportState::Playing;
+        if playing {
+            self.pause(seq);
+        }
+        change(self);
+        if playing {
+            let _ = self.play();
+        }
+    }
+
+    /// The region looped, if looping.
+    fn region(&self) -> Option<(f64, f64)> {
+        if self.looping {
+            Some(self.loop_region.unwrap_or((0.0, self.length)))
+        }
+        else {
+            None
+        }
+    }
+
+    fn cancel(&mut self, seq: &mut Sequencer) {
+        let now = self.now();
+        for e in self.events.drain(..) {
+            // the clock lags the sequencer by up to a block, so leave room for the fade; an event must
+            // not end before it starts
+            let end = e.start.max(now + 2.0 * Self::CUT_FADE);
+            seq.edit(e.event_id, end, Self::CUT_FADE.min(end - e.start));
+        }
+    }
+
+    fn now(&self) -> f64 {
+        self.clock.samples() as f64 / self.sample_rate
+    }
+}
+
+
+impl FrameRenderable for Transport {
+    fn draw_into(&self, frame: &mut ratatui::Frame, area: Rect) {
+        let symbol = match self.state {
+            TransportState::Stopped => "■",
+            TransportState::Playing => "▶",
+            TransportState::Paused => "⏸",
+        };
+        let mut status = format!("{symbol} {:.2} / {:.2}s", self.position(), self.length);
+        if let Some((i, section)) = self.now_playing() {
+            status += &format!("  {} ({}/{})", section.sequence, i + 1, self.sections.len());
+        }
+        match (self.looping, self.loop_region) {
+            (true, Some((start, end))) => status += &format!("  ⟳ {start:.2}-{end:.2}s"),
+            (true, None) => status += "  ⟳",
+            (false, _) => (),
+        }
+        Line::from(status).render(area, frame.buffer_mut());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::Patch;
+
+    const RATE: f64 = 1000.0;
+
+    fn setup(notes: &[f64], length: f64) -> (Transport, Sequencer, Track) {
+        let clock = Clock::new();
+        let mut transport = Transport::new(clock, RATE);
+        let mut seq = Sequencer::new(false, 1);
+        let notes = notes.iter()
+            .map(|&start| SequenceNote { patch: "p".into(), freq: 440.0, start, duration: 1.0 })
+            .collect();
+        transport.load(&mut seq, notes, Vec::new(), length).unwrap();
+        let mut track = Track::new();
+        track.insert_patch("p", Patch::basic());
+        (transport, seq, track)
+    }
+
+    /// Moves the audio clock to `t` seconds and lets the transport schedule.
+    fn run_to(transport: &mut Transport, seq: &mut Sequencer, track: &Track, t: f64) {
+        transport.clock.samples.store((t * RATE).round() as u64, Ordering::Relaxed);
+        transport.update(seq, track, &mut PatchCache::new(120.0), &mut Rnd::from_u64(0)).unwrap();
+    }
+
+    fn starts(transport: &Transport) -> Vec<f64> {
+        transport.events.iter().map(|e| (e.start * RATE).round() / RATE).collect()
+    }
+
+    #[test]
+    fn schedules_within_the_lookahead() {
+        let (mut transport, mut seq, track) = setup(&[0.0, 0.05, 0.5], 2.0);
+        transport.set_looping(&mut seq, false);
+        transport.play().unwrap();
+
+        // playback starts a lookahead after the clock, so nothing is due yet
+        run_to(&mut transport, &mut seq, &track, 0.0);
+        assert!(starts(&transport).is_empty());
+        run_to(&mut transport, &mut seq, &track, 0.1);
+        assert_eq!(starts(&transport), [0.1, 0.15]);
+        run_to(&mut transport, &mut seq, &track, 0.55);
+        assert_eq!(starts(&transport), [0.1, 0.15, 0.6]);
+
+        // one-shot: it stops once the timeline has played out
+        run_to(&mut transport, &mut seq, &track, 2.2);
+        assert_eq!(transport.state(), TransportState::Stopped);
+        assert_eq!(transport.position(), 0.0);
+    }
+
+    #[test]
+    fn carries_on_into_the_next_pass() {
+        let (mut transport, mut seq, track) = setup(&[0.0, 0.5], 1.0);
+        transport.play().unwrap();
+        run_to(&mut transport, &mut seq, &track, 1.05);
+        assert_eq!(starts(&transport), [0.1, 0.6, 1.1]);
+        assert_eq!(transport.state(), TransportState::Playing);
+    }
+
+    #[test]
+    fn wraps_round_the_loop_region() {
+        let (mut transport, mut seq, track) = setup(&[], 2.0);
+        transport.set_loop_region(&mut seq, 0.5, 1.0).unwrap();
+        assert!(transport.set_loop_region(&mut seq, 1.0, 0.5).is_err());
+        assert!(transport.set_loop_region(&mut seq, 0.0, 3.0).is_err());
+        transport.play().unwrap();
+
+        let at = |transport: &mut Transport, seq: &mut Sequencer, t: f64| {
+            run_to(transport, seq, &track, t + Transport::LOOKAHEAD);
+            (transport.position() * RATE).round() / RATE
+        };
+        assert_eq!(at(&mut transport, &mut seq, 0.3), 0.3);
+        assert_eq!(at(&mut transport, &mut seq, 1.2), 0.7);
+        assert_eq!(at(&mut transport, &mut seq, 1.7), 0.7);
+
+        // turning looping off carries on from where it is, to the end
+        transport.set_looping(&mut seq, false);
+        assert_eq!((transport.position() * RATE).round() / RATE, 0.7);
+        assert_eq!(at(&mut transport, &mut seq, 1.7 + 2.0), 0.0);
+        assert_eq!(transport.state(), TransportState::Stopped);
+    }
+
+    #[test]
+    fn seeks_within_bounds() {
+        let (mut transport, mut seq, _) = setup(&[], 2.0);
+        assert!(transport.seek(&mut seq, -1.0).is_err());
+        assert!(transport.seek(&mut seq, 2.0).is_err());
+        transport.seek(&mut seq, 1.5).unwrap();
+        assert_eq!(transport.position(), 1.5);
+        transport.stop(&mut seq);
+        assert_eq!(transport.position(), 0.0);
+
+        let mut empty = Transport::new(Clock::new(), RATE);
+        assert!(empty.play().is_err());
+        assert!(empty.load(&mut seq, Vec::new(), Vec::new(), 0.0).is_err());
+    }
+}
@@ -0,0 +1,447 @@
+use std::time::{Duration, Instant};
+
+use fundsp::hacker::*;
+use serde::{Serialize, Deserialize};
+
+use crate::keyboard::Note;
+
+
+/// Which voice to cut short when a note arrives and every voice is in use.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub enum StealPolicy {
+    #[default]
+    Oldest,
+    /// The voice sounding quietest, as measured while it plays; tails that have died down go first.
+    Quietest,
+    /// As oldest, but a note struck again replaces its own voice if that is still ringing, rather
+    /// than sounding twice.
+    SameNote,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+#[serde(tag="mode")]
+pub enum VoiceMode {
+    #[default]
+    Poly,
+    /// One voice, retriggered by every note.
+    Mono,
+    /// One voice; notes played while another is held change its pitch without retriggering.
+    Legato,
+    /// As legato, gliding between pitches over `time` seconds.
+    Portamento { time: f32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VoiceConfig {
+    pub max_voices: usize,
+    #[serde(default)]
+    pub steal: StealPolicy,
+    #[serde(default)]
+    pub mode: VoiceMode,
+}
+
+impl Default for VoiceConfig {
+    fn default() -> Self {
+        Self { max_voices: 8, steal: StealPolicy::default(), mode: VoiceMode::default() }
+    }
+}
+
+
+pub type VoiceKey = (Note, i32);
+
+/// Values a voice's patch reads from outside, changed while the voice plays.
+pub struct VoiceInputs {
+    pub freq: Shared,
+    /// Positive while the note is held, zero once released; drives the patch's envelopes.
+    pub ctl: Shared,
+    /// Mod wheel position, shared by every voice.
+    pub modulation: Shared,
+    pub velocity: Shared,
+    /// Aftertouch, from the controller's channel or the note's own key pressure.
+    pub pressure: Shared,
+}
+
+struct Voice {
+    key: VoiceKey,
+    /// Frequency of the note before pitch bend.
+    freq: f32,
+    event_id: EventId,
+    inputs: VoiceInputs,
+    order: u64,
+    /// RMS level of the voice's output, kept up to date by the audio thread.
+    level: Shared,
+    released: Option<Instant>,
+    ends: Option<Instant>,
+    /// Released while the sustain pedal was down; released properly when it comes up.
+    sustained: bool,
+}
+
+
+/// Unit for a note of known length, as played by a sequence: held for `duration` seconds, then
+/// released so the patch's envelopes can finish.
+pub fn timed_note(pnet: Net, freq: f32, duration: f64) -> Box<dyn AudioUnit> {
+    let pnet = unit::<U5, U1>(Box::new(pnet));
+    let gate = lfo(move |t: f64| if t < duration { 1.0 } else { 0.0 });
+    Box::new(
+        (constant(freq) | gate | constant(0.0) | constant(1.0) | constant(0.0)) >> pnet
+    )
+}
+
+/// Unit for a note played live, reading its inputs as they change and gliding between pitches over
+/// `glide` seconds, if given.
+pub fn live_note(pnet: Net, inputs: &VoiceInputs, glide: Option<f32>) -> Box<dyn AudioUnit> {
+    let pnet = unit::<U5, U1>(Box::new(pnet));
+    let rest = var(&inputs.ctl) | var(&inputs.modulation) | var(&inputs.velocity) | var(&inputs.pressure);
+    match glide {
+        Some(t) => Box::new((var(&inputs.freq) >> follow(t) | rest) >> pnet),
+        None => Box::new((var(&inputs.freq) | rest) >> pnet),
+    }
+}
+
+
+/// Assigns notes to sequencer events, enforcing the patch's polyphony and playing mode.
+pub struct VoiceAllocator {
+    config: VoiceConfig,
+    voices: Vec<Voice>,
+    /// Notes currently held down, most recent last; in the single voice modes releasing the
+    /// sounding note falls back to the one before it.
+    held: Vec<(VoiceKey, f32, f32)>,
+    counter: u64,
+    modulation: Shared,
+    /// Pitch bend as a frequency ratio.
+    bend: f32,
+    sustain: bool,
+}
+
+impl VoiceAllocator {
+    /// Fade applied to voices that are cut short, to avoid clicks.
+    const STEAL_FADE: f64 = 0.005;
+    /// Pitch bend range either way, in semitones.
+    const BEND_RANGE: f32 = 2.0;
+    /// Time for a voice's measured level to move halfway to a new level, in seconds.
+    const LEVEL_SMOOTHING: f64 = 0.05;
+
+    pub fn new(config: VoiceConfig) -> Self {
+        Self {
+            config,
+            voices: Vec::new(),
+            held: Vec::new(),
+            counter: 0,
+            modulation: shared(0.0),
+            bend: 1.0,
+            sustain: false,
+        }
+    }
+
+    pub fn set_config(&mut self, config: VoiceConfig) {
+        self.config = config;
+    }
+
+    /// Starts (or re-pitches) a voice for `key`. `build` creates the voice's unit, reading the given
+    /// inputs and gliding between pitches by the given time, if any.
+    pub fn note_on<F>(&mut self, seq: &mut Sequencer, key: VoiceKey, freq: f32, velocity: f32, build: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&VoiceInputs, Option<f32>) -> anyhow::Result<Box<dyn AudioUnit>>
+    {
+        self.held.retain(|(k, _, _)| *k != key);
+        self.held.push((key, freq, velocity));
+
+        match self.config.mode {
+            VoiceMode::Poly => {
+                if self.voices.iter().any(|v| v.key == key && v.released.is_none() && !v.sustained) {
+                    return Ok(());
+                }
+                if let StealPolicy::SameNote = self.config.steal
+                    && let Some(i) = self.voices.iter().position(|v| v.key == key) {
+                    self.cut(seq, i);
+                }
+                while !self.voices.is_empty() && self.voices.len() >= self.config.max_voices {
+                    let i = self.steal_candidate();
+                    self.cut(seq, i);
+                }
+                self.start(seq, key, freq, velocity, None, build)
+            },
+            VoiceMode::Mono => {
+                while !self.voices.is_empty() {
+                    self.cut(seq, 0);
+                }
+                self.start(seq, key, freq, velocity, None, build)
+            },
+            VoiceMode::Legato | VoiceMode::Portamento { .. } => {
+                let glide = match self.config.mode {
+                    VoiceMode::Portamento { time } => Some(time),
+                    _ => None,
+                };
+                if let Some(voice) = self.voices.iter_mut().find(|v| v.released.is_none() && !v.sustained) {
+                    voice.key = key;
+                    voice.freq = freq;
+                    voice.inputs.freq.set_value(freq * self.bend);
+                    voice.inputs.velocity.set_value(velocity);
+                    return Ok(());
+                }
+                while !self.voices.is_empty() {
+                    self.cut(seq, 0);
+                }
+                self.start(seq, key, freq, velocity, glide, build)
+            },
+        }
+    }
+
+    /// Releases the voice playing `key`, letting its envelopes run for `release` seconds before it is
+    /// removed.
+    pub fn note_off<F>(&mut self, seq: &mut Sequencer, key: VoiceKey, release: f64, build: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&VoiceInputs, Option<f32>) -> anyhow::Result<Box<dyn AudioUnit>>
+    {
+        self.held.retain(|(k, _, _)| *k != key);
+
+        let Some(i) = self.voices.iter().position(|v| v.key == key && v.released.is_none() && !v.sustained)
+        else {
+            return Ok(());
+        };
+        if self.sustain {
+            self.voices[i].sustained = true;
+            return Ok(());
+        }
+
+        match (self.config.mode, self.held.last().cloned()) {
+            (VoiceMode::Poly, _) | (_, None) => {
+                self.release(seq, i, release);
+                Ok(())
+            },
+            (VoiceMode::Mono, Some((prev, freq, velocity))) => {
+                self.cut(seq, i);
+                self.start(seq, prev, freq, velocity, None, build)
+            },
+            (VoiceMode::Legato | VoiceMode::Portamento { .. }, Some((prev, freq, _))) => {
+                self.voices[i].key = prev;
+                self.voices[i].freq = freq;
+                self.voices[i].inputs.freq.set_value(freq * self.bend);
+                Ok(())
+            },
+        }
+    }
+
+    /// Sets the pitch bend of every voice, -1...1 covering [`Self::BEND_RANGE`] semitones either way.
+    pub fn set_bend(&mut self, bend: f32) {
+        self.bend = 2f32.powf(bend * Self::BEND_RANGE / 12.0);
+        for voice in &self.voices {
+            voice.inputs.freq.set_value(voice.freq * self.bend);
+        }
+    }
+
+    pub fn set_modulation(&mut self, value: f32) {
+        self.modulation.set_value(value);
+    }
+
+    /// Sets the aftertouch of the voice playing `key`, or of every voice if `key` is `None`.
+    pub fn set_pressure(&mut self, key: Option<VoiceKey>, pressure: f32) {
+        for voice in self.voices.iter().filter(|v| key.is_none_or(|k| k == v.key)) {
+            voice.inputs.pressure.set_value(pressure);
+        }
+    }
+
+    /// While the sustain pedal is down released notes keep sounding; lifting it releases them.
+    pub fn set_sustain(&mut self, seq: &mut Sequencer, sustain: bool, release: f64) {
+        self.sustain = sustain;
+        if sustain {
+            return;
+        }
+        for i in 0..self.voices.len() {
+            if self.voices[i].sustained {
+                self.voices[i].sustained = false;
+                self.release(seq, i, release);
+            }
+        }
+    }
+
+    /// Forgets voices whose release tails have finished.
+    pub fn update(&mut self) {
+        let now = Instant::now();
+        self.voices.retain(|v| v.ends.is_none_or(|t| t > now));
+    }
+
+    fn start<F>(&mut self, seq: &mut Sequencer, key: VoiceKey, freq: f32, velocity: f32, glide: Option<f32>, build: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&VoiceInputs, Option<f32>) -> anyhow::Result<Box<dyn AudioUnit>>
+    {
+        let inputs = VoiceInputs {
+            freq: shared(freq * self.bend),
+            ctl: shared(1.0),
+            modulation: self.modulation.clone(),
+            velocity: shared(velocity),
+            pressure: shared(0.0),
+        };
+        let level = shared(0.0);
+        let unit = Box::new(unit::<U0, U1>(build(&inputs, glide)?) >> monitor(&level, Meter::Rms(Self::LEVEL_SMOOTHING)));
+        let event_id = seq.push_relative(0.0, f64::INFINITY, Fade::Power, 0.0, 0.0, unit);
+        self.counter += 1;
+        self.voices.push(Voice {
+            key,
+            freq,
+            event_id,
+            inputs,
+            order: self.counter,
+            level,
+            released: None,
+            ends: None,
+            sustained: false,
+        });
+        Ok(())
+    }
+
+    fn release(&mut self, seq: &mut Sequencer, i: usize, release: f64) {
+        let voice = &mut self.voices[i];
+        voice.inputs.ctl.set_value(0.0);
+        // the envelope reaches silence after `release`; the short fade only covers patches without one
+        let release = release + Self::STEAL_FADE;
+        seq.edit_relative(voice.event_id, release, Self::STEAL_FADE);
+        let now = Instant::now();
+        voice.released = Some(now);
+        voice.ends = Some(now + Duration::from_secs_f64(release));
+    }
+
+    fn cut(&mut self, seq: &mut Sequencer, i: usize) {
+        let voice = self.voices.remove(i);
+        seq.edit_relative(voice.event_id, Self::STEAL_FADE, Self::STEAL_FADE);
+    }
+
+    fn steal_candidate(&self) -> usize {
+        let oldest = || {
+            self.voices.iter().enumerate().min_by_key(|(_, v)| v.order).map(|(i, _)| i).unwrap_or(0)
+        };
+        match self.config.steal {
+            // a voice of the same note has already been replaced
+            StealPolicy::Oldest | StealPolicy::SameNote => oldest(),
+            StealPolicy::Quietest => {
+                self.voices.iter().enumerate()
+                    .min_by(|(_, a), (_, b)| a.level.value().total_cmp(&b.level.value()).then(a.order.cmp(&b.order)))
+                    .map(|(i, _)| i)
+                    .unwrap_or_else(oldest)
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::{NetContext, Patch};
+
+    const A4: VoiceKey = (Note::A, 4);
+    const B4: VoiceKey = (Note::B, 4);
+    const C5: VoiceKey = (Note::C, 5);
+
+    fn allocator(max_voices: usize, steal: StealPolicy, mode: VoiceMode) -> (VoiceAllocator, Sequencer) {
+        (VoiceAllocator::new(VoiceConfig { max_voices, steal, mode }), Sequencer::new(false, 1))
+    }
+
+    fn on(voices: &mut VoiceAllocator, seq: &mut Sequencer, key: VoiceKey) {
+        let freq = key.0.to_freq_octave(key.1);
+        voices.note_on(seq, key, freq, 1.0, |_, _| Ok(Box::new(dc(0.0)))).unwrap();
+    }
+
+    fn off(voices: &mut VoiceAllocator, seq: &mut Sequencer, key: VoiceKey) {
+        voices.note_off(seq, key, 1.0, |_, _| Ok(Box::new(dc(0.0)))).unwrap();
+    }
+
+    fn keys(voices: &VoiceAllocator) -> Vec<VoiceKey> {
+        voices.voices.iter().map(|v| v.key).collect()
+    }
+
+    #[test]
+    fn steals_by_policy() {
+        let (mut voices, mut seq) = allocator(2, StealPolicy::Oldest, VoiceMode::Poly);
+        for key in [A4, B4, C5] {
+            on(&mut voices, &mut seq, key);
+        }
+        assert_eq!(keys(&voices), [B4, C5]);
+
+        let (mut voices, mut seq) = allocator(2, StealPolicy::Quietest, VoiceMode::Poly);
+        on(&mut voices, &mut seq, A4);
+        on(&mut voices, &mut seq, B4);
+        voices.voices[0].level.set_value(0.5);
+        voices.voices[1].level.set_value(0.1);
+        on(&mut voices, &mut seq, C5);
+        assert_eq!(keys(&voices), [A4, C5]);
+
+        // striking a ringing note again: its tail rings on, unless the policy replaces it
+        let (mut voices, mut seq) = allocator(4, StealPolicy::Oldest, VoiceMode::Poly);
+        on(&mut voices, &mut seq, A4);
+        off(&mut voices, &mut seq, A4);
+        on(&mut voices, &mut seq, A4);
+        assert_eq!(keys(&voices), [A4, A4]);
+
+        let (mut voices, mut seq) = allocator(4, StealPolicy::SameNote, VoiceMode::Poly);
+        on(&mut voices, &mut seq, A4);
+        off(&mut voices, &mut seq, A4);
+        on(&mut voices, &mut seq, A4);
+        assert_eq!(keys(&voices), [A4]);
+        assert!(voices.voices[0].released.is_none());
+    }
+
+    #[test]
+    fn keeps_to_the_polyphony_limit() {
+        let (mut voices, mut seq) = allocator(3, StealPolicy::Oldest, VoiceMode::Poly);
+        for octave in 0..8 {
+            on(&mut voices, &mut seq, (Note::C, octave));
+            assert!(voices.voices.len() <= 3);
+        }
+        // holding a note already sounding doesn't start another voice
+        on(&mut voices, &mut seq, (Note::C, 7));
+        assert_eq!(voices.voices.len(), 3);
+    }
+
+    #[test]
+    fn plays_single_voice_modes() {
+        // mono retriggers, and falls back to the note still held
+        let (mut voices, mut seq) = allocator(8, StealPolicy::Oldest, VoiceMode::Mono);
+        on(&mut voices, &mut seq, A4);
+        on(&mut voices, &mut seq, B4);
+        assert_eq!(keys(&voices), [B4]);
+        let order = voices.voices[0].order;
+        off(&mut voices, &mut seq, B4);
+        assert_eq!(keys(&voices), [A4]);
+        assert!(voices.voices[0].order > order);
+
+        // legato keeps the voice, changing its pitch
+        let (mut voices, mut seq) = allocator(8, StealPolicy::Oldest, VoiceMode::Legato);
+        on(&mut voices, &mut seq, A4);
+        let order = voices.voices[0].order;
+        on(&mut voices, &mut seq, B4);
+        assert_eq!(keys(&voices), [B4]);
+        assert_eq!(voices.voices[0].order, order);
+        assert_eq!(voices.voices[0].inputs.freq.value(), Note::B.to_freq_octave(4));
+        off(&mut voices, &mut seq, B4);
+        assert_eq!(voices.voices[0].inputs.freq.value(), Note::A.to_freq_octave(4));
+        off(&mut voices, &mut seq, A4);
+        assert!(voices.voices[0].released.is_some());
+
+        // portamento is legato with a glide
+        let (mut voices, mut seq) = allocator(8, StealPolicy::Oldest, VoiceMode::Portamento { time: 0.2 });
+        let mut glide = None;
+        voices.note_on(&mut seq, A4, 440.0, 1.0, |_, g| { glide = g; Ok(Box::new(dc(0.0))) }).unwrap();
+        assert_eq!(glide, Some(0.2));
+        on(&mut voices, &mut seq, B4);
+        assert_eq!(voices.voices.len(), 1);
+    }
+
+    #[test]
+    fn timed_note_holds_then_releases() {
+        const RATE: f64 = 1000.0;
+        // a saw through an envelope releasing over 0.2 s
+        let patch = Patch::basic();
+        let pnet = patch.create_net(&mut NetContext::new(120.0)).unwrap();
+        let mut unit = timed_note(pnet, 50.0, 0.5);
+        unit.set_sample_rate(RATE);
+        let samples: Vec<f32> = (0..1000).map(|_| unit.get_mono().abs()).collect();
+        let peak = |from: f64, to: f64| samples[(from * RATE) as usize..(to * RATE) as usize].iter().cloned().fold(0.0, f32::max);
+
+        let held = peak(0.3, 0.5);
+        assert!(held > 0.01, "held at the sustain level");
+        assert!(peak(0.5, 0.55) > held * 0.5, "released, not cut off");
+        assert!(peak(0.65, 0.7) < held * 0.5, "dying away");
+        assert!(peak(0.75, 1.0) < held * 0.01, "silent once released");
+    }
+}
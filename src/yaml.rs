@@ -0,0 +1,35 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::anyhow;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+
+pub fn load<T: DeserializeOwned>(path: &str) -> anyhow::Result<T> {
+    let f = OpenOptions::new().read(true).open(path)?;
+    let rv = serde_yaml::from_reader(f)?;
+    Ok(rv)
+}
+
+/// Writes `value` to `path` as YAML. The file is written beside `path` and renamed over it, so a
+/// failed save never leaves a half written file behind.
+pub fn save<T: Serialize>(value: &T, path: &str) -> anyhow::Result<()> {
+    let yaml = serde_yaml::to_string(value)?;
+
+    let path = Path::new(path);
+    let name = path.file_name().ok_or_else(|| anyhow!("\"{}\" is not a file path", path.display()))?;
+    let tmp = path.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+
+    let result = File::create(&tmp)
+        .and_then(|mut f| {
+            f.write_all(yaml.as_bytes())?;
+            f.sync_all()
+        })
+        .and_then(|()| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    Ok(result?)
+}